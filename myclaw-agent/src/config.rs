@@ -1,3 +1,4 @@
+use myclaw_common::protocol::Codec;
 use serde::Deserialize;
 use std::path::Path;
 
@@ -11,8 +12,22 @@ pub struct AgentSettings {
     pub relay_url: String,
     pub gateway_url: String,
     pub agent_id: String,
+    /// Key registered for this agent_id in relay.toml
+    pub relay_key: String,
     pub reconnect_base_ms: u64,
     pub reconnect_max_ms: u64,
+    /// `msgpack` to ask the relay for binary frames; JSON otherwise
+    #[serde(default)]
+    pub codec: Codec,
+    /// Ask the relay to deflate large messages both ways
+    #[serde(default)]
+    pub compress: bool,
+    /// Address serving Prometheus metrics at `/metrics`; off if unset
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    /// OTLP/gRPC collector to export tracing spans to (needs the `otlp` build feature); off if unset
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
 }
 
 impl AgentConfig {
@@ -3,8 +3,11 @@ mod tunnel;
 
 use std::path::PathBuf;
 use clap::Parser;
+use metrics_exporter_prometheus::PrometheusBuilder;
 use tracing::{info, error};
 
+use myclaw_common::trace;
+
 use config::AgentConfig;
 
 #[derive(Parser)]
@@ -15,16 +18,20 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter("myclaw_agent=debug,info")
-        .init();
-
     let cli = Cli::parse();
     let cfg = AgentConfig::load(&cli.config)?;
+    trace::init("myclaw_agent=debug,info", "myclaw-agent", cfg.agent.otlp_endpoint.as_deref())?;
 
     info!("agent: relay={}, gateway={}, id={}",
         cfg.agent.relay_url, cfg.agent.gateway_url, cfg.agent.agent_id);
 
+    if let Some(listen) = &cfg.agent.metrics_listen {
+        PrometheusBuilder::new()
+            .with_http_listener(listen.parse::<std::net::SocketAddr>()?)
+            .install()?;
+        info!("agent: serving metrics on http://{}/metrics", listen);
+    }
+
     let mut backoff = cfg.agent.reconnect_base_ms;
 
     loop {
@@ -37,6 +44,7 @@ async fn main() -> anyhow::Result<()> {
                 error!("agent: tunnel error: {}", e);
             }
         }
+        metrics::gauge!("myclaw_agent_tunnel_uptime_seconds").set(0.0);
 
         info!("agent: reconnecting in {}ms", backoff);
         tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
@@ -1,13 +1,25 @@
+use std::collections::HashMap;
 use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, Instant};
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{info, warn};
+use tracing::{debug, field, info, info_span, warn};
 
+use myclaw_common::compress::SentCounters;
+use myclaw_common::protocol::{
+    negotiate_version, Capability, Decoder, Encoder, MIN_RELAY_VERSION, PROTOCOL_VERSION,
+};
+use myclaw_common::trace::{self, FrameHead, TraceContext};
 use myclaw_common::RelayFrame;
 use crate::config::AgentSettings;
 
-/// Connect to relay, perform handshake, then connect to local gateway
-/// and bridge messages bidirectionally.
+/// Requests of one gateway link whose trace is kept for their reply; past this,
+/// the oldest is forgotten, as one the gateway never finished
+const MAX_TRACED_REQUESTS: usize = 1024;
+
+/// Connect to relay, perform handshake, then bridge each relayed server
+/// to its own connection to the local gateway.
 pub async fn run_tunnel(cfg: &AgentSettings) -> anyhow::Result<()> {
     // --- Connect to relay ---
     info!("tunnel: connecting to relay at {}", cfg.relay_url);
@@ -16,16 +28,33 @@ pub async fn run_tunnel(cfg: &AgentSettings) -> anyhow::Result<()> {
     info!("tunnel: connected to relay");
 
     // --- Handshake ---
+    let mut capabilities = cfg.codec.capabilities();
+    if cfg.compress {
+        capabilities.push(Capability::Deflate);
+    }
     let hello = serde_json::to_string(&RelayFrame::AgentHello {
         agent_id: cfg.agent_id.clone(),
+        key: cfg.relay_key.clone(),
+        version: PROTOCOL_VERSION,
+        capabilities,
     })?;
     relay_tx.send(Message::Text(hello)).await?;
 
+    let mut encoder = Encoder::counted(SentCounters::new("myclaw_agent", "relay"));
+    let mut decoder = Decoder::default();
     match relay_rx.next().await {
         Some(Ok(Message::Text(text))) => {
             match serde_json::from_str::<RelayFrame>(&text) {
-                Ok(RelayFrame::AgentWelcome { agent_id }) => {
-                    info!("tunnel: relay welcomed agent '{}'", agent_id);
+                Ok(RelayFrame::AgentWelcome { agent_id, version, capabilities }) => {
+                    if negotiate_version(version, MIN_RELAY_VERSION).is_none() {
+                        anyhow::bail!("relay speaks unsupported protocol version {}", version);
+                    }
+                    info!("tunnel: relay welcomed agent '{}' (protocol v{}, {:?})", agent_id, version, capabilities);
+                    encoder.negotiate(&capabilities);
+                    decoder.negotiate(&capabilities);
+                }
+                Ok(RelayFrame::Rejected { code, message }) => {
+                    anyhow::bail!("relay rejected agent ({:?}): {}", code, message);
                 }
                 _ => {
                     anyhow::bail!("unexpected relay response: {}", text);
@@ -37,50 +66,167 @@ pub async fn run_tunnel(cfg: &AgentSettings) -> anyhow::Result<()> {
         }
     }
 
-    // --- Connect to local gateway ---
-    info!("tunnel: connecting to gateway at {}", cfg.gateway_url);
-    let (gw_ws, _) = connect_async(&cfg.gateway_url).await?;
-    let (mut gw_tx, mut gw_rx) = gw_ws.split();
-    info!("tunnel: connected to gateway");
+    // --- Multiplex servers onto gateway links ---
+    // server_id → sender into that server's gateway link
+    let mut links: HashMap<String, LinkTx> = HashMap::new();
+    // Frames from all gateway links back to the relay
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<RelayFrame>();
 
-    // --- Bidirectional bridge ---
-    // relay → gateway
-    let relay_to_gw = async {
-        while let Some(Ok(msg)) = relay_rx.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if gw_tx.send(Message::Text(text)).await.is_err() {
-                        break;
+    // Reported on /metrics; reset by the caller once the tunnel ends
+    let connected_at = Instant::now();
+    let uptime = metrics::gauge!("myclaw_agent_tunnel_uptime_seconds");
+    let mut uptime_tick = interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            msg = relay_rx.next() => {
+                let msg = match msg {
+                    Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => msg,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Err(e)) => return Err(e.into()),
+                    Some(Ok(_)) => continue,
+                };
+                let (server_id, payload, traceparent) = match decoder.decode::<RelayFrame>(&msg) {
+                    Ok(RelayFrame::Forward { server_id, payload, traceparent }) => {
+                        (server_id, Message::Text(payload), traceparent)
+                    }
+                    Ok(RelayFrame::ForwardBinary { server_id, payload, traceparent }) => {
+                        (server_id, Message::Binary(payload), traceparent)
                     }
+                    Ok(RelayFrame::Close { server_id }) => {
+                        // Dropping the sender ends the link task and closes its gateway socket
+                        if links.remove(&server_id).is_some() {
+                            info!("tunnel: {} closed by relay", server_id);
+                        }
+                        continue;
+                    }
+                    Ok(frame) => {
+                        warn!("tunnel: unexpected relay frame: {:?}", frame);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("tunnel: bad relay frame: {}", e);
+                        continue;
+                    }
+                };
+                let link = links
+                    .entry(server_id.clone())
+                    .or_insert_with(|| open_link(cfg, &server_id, out_tx.clone()));
+                if link.is_closed() {
+                    *link = open_link(cfg, &server_id, out_tx.clone());
                 }
-                Message::Close(_) => break,
-                _ => {}
+                let _ = link.send((payload, traceparent));
+            }
+            Some(frame) = out_rx.recv() => {
+                relay_tx.send(encoder.encode(&frame)?).await?;
+            }
+            _ = uptime_tick.tick() => {
+                uptime.set(connected_at.elapsed().as_secs_f64());
             }
         }
-    };
+    }
 
-    // gateway → relay
-    let gw_to_relay = async {
-        while let Some(Ok(msg)) = gw_rx.next().await {
-            match msg {
-                Message::Text(text) => {
-                    if relay_tx.send(Message::Text(text)).await.is_err() {
+    warn!("tunnel: relay connection closed");
+    Ok(())
+}
+
+/// Payloads for one gateway link, with the relay's hop in the trace of their request
+type LinkTx = mpsc::UnboundedSender<(Message, Option<TraceContext>)>;
+
+/// Spawn a gateway link for one relayed server; payloads sent before the
+/// gateway connection is up are queued in the returned channel.
+fn open_link(
+    cfg: &AgentSettings,
+    server_id: &str,
+    out_tx: mpsc::UnboundedSender<RelayFrame>,
+) -> LinkTx {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let gateway_url = cfg.gateway_url.clone();
+    let server_id = server_id.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = run_link(&gateway_url, &server_id, rx, &out_tx).await {
+            warn!("tunnel: gateway link for {} failed: {}", server_id, e);
+        }
+        let _ = out_tx.send(RelayFrame::Close { server_id });
+    });
+    tx
+}
+
+/// Bridge one relayed server ↔ its own gateway connection until either side closes.
+async fn run_link(
+    gateway_url: &str,
+    server_id: &str,
+    mut rx: mpsc::UnboundedReceiver<(Message, Option<TraceContext>)>,
+    out_tx: &mpsc::UnboundedSender<RelayFrame>,
+) -> anyhow::Result<()> {
+    info!("tunnel: connecting {} to gateway at {}", server_id, gateway_url);
+    let (gw_ws, _) = connect_async(gateway_url).await?;
+    let (mut gw_tx, mut gw_rx) = gw_ws.split();
+    info!("tunnel: {} connected to gateway", server_id);
+
+    // request_id → this hop in the request's trace, carried on its reply chunks, and when it was sent
+    let mut traces: HashMap<String, (TraceContext, Instant)> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            // relay → gateway
+            payload = rx.recv() => {
+                let Some((payload, traceparent)) = payload else {
+                    let _ = gw_tx.close().await;
+                    break;
+                };
+                if let Some(head) = FrameHead::peek(&payload) {
+                    if let Some(request_id) = head.traced() {
+                        let span = info_span!("link", %server_id, kind = %head.kind, request_id, trace_id = field::Empty);
+                        let trace = trace::hop(&span, traceparent.or(head.traceparent));
+                        span.in_scope(|| debug!("tunnel: {} → gateway", head.kind));
+                        if traces.len() >= MAX_TRACED_REQUESTS {
+                            let oldest = traces.iter().min_by_key(|(_, (_, sent))| *sent).map(|(id, _)| id.clone());
+                            if let Some(oldest) = oldest {
+                                traces.remove(&oldest);
+                            }
+                        }
+                        traces.insert(request_id.to_string(), (trace, Instant::now()));
+                    } else if head.kind == "cancel" {
+                        // The server has dropped the request, so whatever else comes of it goes nowhere
+                        if let Some(request_id) = &head.request_id {
+                            traces.remove(request_id);
+                        }
+                    }
+                }
+                gw_tx.send(payload).await?;
+            }
+            // gateway → relay
+            msg = gw_rx.next() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | None => {
+                        warn!("tunnel: gateway closed link for {}", server_id);
                         break;
                     }
+                    Some(Err(e)) => return Err(e.into()),
+                    // Text or Binary, as the gateway sent it; control messages are skipped
+                    Some(Ok(msg)) => {
+                        let traceparent = FrameHead::peek(&msg).and_then(|head| {
+                            let request_id = head.request_id.as_deref()?;
+                            if head.traced().is_none() {
+                                return traces.get(request_id).map(|(trace, _)| *trace);
+                            }
+                            // The reply is over: its last frame gets a span of its own
+                            let parent = traces.remove(request_id).map(|(trace, _)| trace);
+                            let span = info_span!("link", %server_id, kind = %head.kind, request_id, trace_id = field::Empty);
+                            let trace = trace::hop(&span, parent);
+                            span.in_scope(|| debug!("tunnel: {} → relay", head.kind));
+                            Some(trace)
+                        });
+                        if let Some(frame) = RelayFrame::forward(server_id, msg, traceparent) {
+                            if out_tx.send(frame).is_err() {
+                                break;
+                            }
+                        }
+                    }
                 }
-                Message::Close(_) => break,
-                _ => {}
             }
         }
-    };
-
-    tokio::select! {
-        _ = relay_to_gw => {
-            warn!("tunnel: relay connection closed");
-        }
-        _ = gw_to_relay => {
-            warn!("tunnel: gateway connection closed");
-        }
     }
 
     Ok(())
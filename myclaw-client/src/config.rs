@@ -1,4 +1,5 @@
 use clap::Parser;
+use myclaw_common::protocol::{Codec, DisconnectPolicy};
 use serde::Deserialize;
 use std::path::PathBuf;
 
@@ -18,6 +19,41 @@ pub struct ClientConfig {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerAddr {
     pub url: String,
+    /// Bearer token from the server's user table; the server accepts no client without one
+    pub token: String,
+    #[serde(default = "default_reconnect_base_ms")]
+    pub reconnect_base_ms: u64,
+    #[serde(default = "default_reconnect_max_ms")]
+    pub reconnect_max_ms: u64,
+    /// `msgpack` to ask the server for binary frames; JSON otherwise
+    #[serde(default)]
+    pub codec: Codec,
+    /// Ask the server to deflate large messages both ways
+    #[serde(default)]
+    pub compress: bool,
+    /// Seconds between latency probes shown in the status bar
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// What the server does with a chat cut off by a gateway disconnect, until `/ondisconnect`
+    /// changes it
+    #[serde(default = "default_on_disconnect")]
+    pub on_disconnect: DisconnectPolicy,
+}
+
+fn default_reconnect_base_ms() -> u64 {
+    1000
+}
+
+fn default_reconnect_max_ms() -> u64 {
+    30000
+}
+
+fn default_ping_interval_secs() -> u64 {
+    15
+}
+
+fn default_on_disconnect() -> DisconnectPolicy {
+    DisconnectPolicy::Retry
 }
 
 impl ClientConfig {
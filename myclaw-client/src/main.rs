@@ -20,12 +20,12 @@ async fn main() -> anyhow::Result<()> {
     let (outbound_tx, outbound_rx) = mpsc::channel(64);
     let (inbound_tx, inbound_rx) = mpsc::channel(64);
 
-    let url = config.server.url.clone();
+    let server = config.server.clone();
     let ws_task = tokio::spawn(async move {
-        ws::run(&url, outbound_rx, inbound_tx).await
+        ws::run(&server, outbound_rx, inbound_tx).await
     });
 
-    let tui_result = tui::run(inbound_rx, outbound_tx).await;
+    let tui_result = tui::run(inbound_rx, outbound_tx, config.server.on_disconnect).await;
 
     ws_task.abort();
     tui_result
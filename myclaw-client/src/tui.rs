@@ -1,51 +1,98 @@
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use myclaw_common::protocol::{ConversationSummary, DisconnectPolicy, Role};
 use myclaw_common::{ClientMessage, ServerMessage};
 use ratatui::prelude::*;
 use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Wrap};
 use tokio::sync::mpsc;
 
+use crate::ws::WsEvent;
+
 pub enum ChatEntry {
     User(String),
     Bot(String),
     System(String),
 }
 
+/// State of the client ↔ server socket.
+enum Link {
+    Connecting,
+    Online,
+    Reconnecting,
+    Stopped,
+}
+
 struct App {
     input: String,
     messages: Vec<ChatEntry>,
     scroll: u16,
+    /// Latest request whose reply has not finished; Esc cancels it
+    in_flight: Option<String>,
+    /// Reply being streamed: request id and its entry in `messages`
+    streaming: Option<(String, usize)>,
+    link: Link,
     gateway_connected: bool,
+    /// Round trip from the server to the gateway, as last reported
+    gateway_rtt_ms: Option<u64>,
+    /// Hops before the gateway when the server goes through myclaw-relay
+    relay_reachable: Option<bool>,
+    agent_attached: Option<bool>,
+    /// Round trip to the server, once measured on the current connection
+    latency_ms: Option<u64>,
+    user_id: Option<String>,
+    /// The user's conversations, most recently active first
+    conversations: Vec<ConversationSummary>,
+    /// Conversation being shown and chatted in; the server's default until one is picked
+    conversation_id: Option<String>,
+    /// `seq` of the oldest restored entry; `None` until history is loaded
+    history_cursor: Option<u64>,
+    history_has_more: bool,
+    /// Sent with every chat; `/ondisconnect` switches it
+    on_disconnect: DisconnectPolicy,
     outbound_tx: mpsc::Sender<ClientMessage>,
 }
 
 impl App {
-    fn new(tx: mpsc::Sender<ClientMessage>) -> Self {
+    fn new(tx: mpsc::Sender<ClientMessage>, on_disconnect: DisconnectPolicy) -> Self {
         Self {
             input: String::new(),
             messages: vec![ChatEntry::System("Welcome to MyClaw!".into())],
             scroll: 0,
+            in_flight: None,
+            streaming: None,
+            link: Link::Connecting,
             gateway_connected: false,
+            gateway_rtt_ms: None,
+            relay_reachable: None,
+            agent_attached: None,
+            latency_ms: None,
+            user_id: None,
+            conversations: Vec::new(),
+            conversation_id: None,
+            history_cursor: None,
+            history_has_more: false,
+            on_disconnect,
             outbound_tx: tx,
         }
     }
 }
 
 pub async fn run(
-    mut inbound_rx: mpsc::Receiver<ServerMessage>,
+    mut inbound_rx: mpsc::Receiver<WsEvent>,
     outbound_tx: mpsc::Sender<ClientMessage>,
+    on_disconnect: DisconnectPolicy,
 ) -> anyhow::Result<()> {
     crossterm::terminal::enable_raw_mode()?;
     let mut stdout = std::io::stdout();
     crossterm::execute!(stdout, crossterm::terminal::EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    let mut app = App::new(outbound_tx);
+    let mut app = App::new(outbound_tx, on_disconnect);
     let tick = std::time::Duration::from_millis(50);
 
     loop {
         terminal.draw(|f| draw(f, &app))?;
-        while let Ok(msg) = inbound_rx.try_recv() {
-            handle_server_msg(&mut app, msg);
+        while let Ok(event) = inbound_rx.try_recv() {
+            handle_ws_event(&mut app, event).await;
         }
         if !event::poll(tick)? {
             continue;
@@ -54,10 +101,12 @@ pub async fn run(
             match (key.code, key.modifiers) {
                 (KeyCode::Char('c'), KeyModifiers::CONTROL) => break,
                 (KeyCode::Enter, _) => send_input(&mut app).await,
+                (KeyCode::Esc, _) => cancel_reply(&mut app).await,
                 (KeyCode::Char(c), _) => app.input.push(c),
                 (KeyCode::Backspace, _) => { app.input.pop(); }
                 (KeyCode::Up, _) => app.scroll = app.scroll.saturating_add(1),
                 (KeyCode::Down, _) => app.scroll = app.scroll.saturating_sub(1),
+                (KeyCode::PageUp, _) => load_older(&mut app).await,
                 _ => {}
             }
         }
@@ -77,35 +126,344 @@ async fn send_input(app: &mut App) {
     if text.is_empty() {
         return;
     }
-    app.messages.push(ChatEntry::User(text.clone()));
     app.input.clear();
-    let msg = ClientMessage::new_chat(&text);
+    if let Some(command) = text.strip_prefix('/') {
+        run_command(app, command).await;
+        return;
+    }
+    app.messages.push(ChatEntry::User(text.clone()));
+    match app.link {
+        Link::Online => {}
+        Link::Stopped => {
+            app.messages.push(ChatEntry::System("Not connected, message dropped".into()));
+            return;
+        }
+        Link::Connecting | Link::Reconnecting => {
+            app.messages.push(ChatEntry::System("Offline, message queued".into()));
+        }
+    }
+    let msg = ClientMessage::new_chat(&text, app.conversation_id.clone(), app.on_disconnect);
+    if let ClientMessage::Chat { id, .. } = &msg {
+        app.in_flight = Some(id.clone());
+    }
     let _ = app.outbound_tx.send(msg).await;
 }
 
-fn handle_server_msg(app: &mut App, msg: ServerMessage) {
-    match msg {
-        ServerMessage::ChatReply { content, done, .. } => {
-            if done {
-                app.messages.push(ChatEntry::Bot(content));
+/// Conversation commands: `/new [name]`, `/list`, `/switch <n>`, `/rename <name>`, `/delete`;
+/// and `/ondisconnect [retry|fail]` for the chats that follow.
+async fn run_command(app: &mut App, command: &str) {
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    let arg = arg.trim();
+    let msg = match name {
+        "new" => ClientMessage::CreateConversation {
+            name: (!arg.is_empty()).then(|| arg.to_string()),
+        },
+        "list" => {
+            if app.conversations.is_empty() {
+                app.messages.push(ChatEntry::System("No conversations yet".into()));
+            }
+            for (n, conv) in app.conversations.iter().enumerate() {
+                let current = if app.conversation_id.as_ref() == Some(&conv.conversation_id) { "*" } else { " " };
+                app.messages.push(ChatEntry::System(format!(
+                    "{current}{} {} ({} messages)",
+                    n + 1,
+                    conversation_label(conv),
+                    conv.message_count
+                )));
+            }
+            return;
+        }
+        "switch" => {
+            let picked = arg
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| app.conversations.get(n.wrapping_sub(1)))
+                .map(|conv| conv.conversation_id.clone());
+            match picked {
+                Some(conversation_id) => switch_conversation(app, conversation_id).await,
+                None => app.messages.push(ChatEntry::System("Usage: /switch <number from /list>".into())),
+            }
+            return;
+        }
+        "ondisconnect" => {
+            match arg {
+                "retry" => app.on_disconnect = DisconnectPolicy::Retry,
+                "fail" => app.on_disconnect = DisconnectPolicy::Fail,
+                "" => {}
+                _ => {
+                    app.messages.push(ChatEntry::System("Usage: /ondisconnect [retry|fail]".into()));
+                    return;
+                }
+            }
+            let policy = match app.on_disconnect {
+                DisconnectPolicy::Retry => "retry",
+                DisconnectPolicy::Fail => "fail",
+            };
+            app.messages.push(ChatEntry::System(format!("On gateway disconnect: {policy}")));
+            return;
+        }
+        "rename" | "delete" => {
+            let Some(conversation_id) = app.conversation_id.clone() else {
+                app.messages.push(ChatEntry::System("No conversation selected".into()));
+                return;
+            };
+            if name == "delete" {
+                ClientMessage::DeleteConversation { conversation_id }
+            } else if arg.is_empty() {
+                app.messages.push(ChatEntry::System("Usage: /rename <name>".into()));
+                return;
             } else {
-                // Streaming: append to last bot message or create new
-                if let Some(ChatEntry::Bot(ref mut s)) = app.messages.last_mut() {
-                    s.push_str(&content);
-                } else {
+                ClientMessage::RenameConversation { conversation_id, name: arg.to_string() }
+            }
+        }
+        _ => {
+            app.messages.push(ChatEntry::System(
+                "Commands: /new [name], /list, /switch <n>, /rename <name>, /delete, /ondisconnect [retry|fail]".into(),
+            ));
+            return;
+        }
+    };
+    let _ = app.outbound_tx.send(msg).await;
+}
+
+/// Clear the chat and show another conversation from its latest page of history.
+async fn switch_conversation(app: &mut App, conversation_id: String) {
+    app.messages.clear();
+    app.scroll = 0;
+    let label = app
+        .conversations
+        .iter()
+        .find(|c| c.conversation_id == conversation_id)
+        .map_or_else(|| conversation_id.clone(), conversation_label);
+    app.messages.push(ChatEntry::System(format!("Conversation: {label}")));
+    open_conversation(app, conversation_id).await;
+}
+
+async fn open_conversation(app: &mut App, conversation_id: String) {
+    app.conversation_id = Some(conversation_id.clone());
+    app.history_cursor = None;
+    app.history_has_more = false;
+    app.streaming = None;
+    let msg = ClientMessage::LoadHistory {
+        conversation_id,
+        before: None,
+        limit: None,
+    };
+    let _ = app.outbound_tx.send(msg).await;
+}
+
+fn conversation_label(conv: &ConversationSummary) -> String {
+    conv.name
+        .clone()
+        .unwrap_or_else(|| conv.conversation_id.chars().take(8).collect())
+}
+
+/// Ask the server to stop the reply that is still on its way.
+async fn cancel_reply(app: &mut App) {
+    let Some(request_id) = app.in_flight.take() else {
+        return;
+    };
+    let _ = app.outbound_tx.send(ClientMessage::Cancel { request_id }).await;
+}
+
+/// Request the page of history preceding the oldest restored entry.
+async fn load_older(app: &mut App) {
+    let (Some(conversation_id), Some(before)) = (app.conversation_id.clone(), app.history_cursor)
+    else {
+        return;
+    };
+    if !app.history_has_more {
+        return;
+    }
+    let msg = ClientMessage::LoadHistory {
+        conversation_id,
+        before: Some(before),
+        limit: None,
+    };
+    let _ = app.outbound_tx.send(msg).await;
+}
+
+async fn handle_ws_event(app: &mut App, event: WsEvent) {
+    match event {
+        WsEvent::Connected => {
+            if matches!(app.link, Link::Reconnecting) {
+                app.messages.push(ChatEntry::System("Reconnected".into()));
+            }
+            app.link = Link::Online;
+        }
+        WsEvent::Reconnecting { delay_ms, queued } => {
+            if !matches!(app.link, Link::Reconnecting) {
+                app.messages.push(ChatEntry::System("Connection to server lost".into()));
+            }
+            app.link = Link::Reconnecting;
+            app.gateway_connected = false;
+            app.relay_reachable = None;
+            app.agent_attached = None;
+            app.latency_ms = None;
+            let mut note = format!("Reconnecting in {:.1}s", delay_ms as f64 / 1000.0);
+            if queued > 0 {
+                note.push_str(&format!(", {queued} message(s) queued"));
+            }
+            app.messages.push(ChatEntry::System(note));
+        }
+        WsEvent::Stopped(reason) => {
+            app.link = Link::Stopped;
+            app.gateway_connected = false;
+            app.relay_reachable = None;
+            app.agent_attached = None;
+            app.latency_ms = None;
+            app.messages.push(ChatEntry::System(format!("Disconnected: {reason}")));
+        }
+        WsEvent::Latency(ms) => app.latency_ms = Some(ms),
+        WsEvent::Message(msg) => handle_server_msg(app, msg).await,
+    }
+}
+
+async fn handle_server_msg(app: &mut App, msg: ServerMessage) {
+    match msg {
+        ServerMessage::ChatReply { request_id, conversation_id, content, done, cancelled, .. } => {
+            if done && app.in_flight.as_ref() == Some(&request_id) {
+                app.in_flight = None;
+            }
+            match &app.conversation_id {
+                // Reply to a conversation we have since switched away from; it is in its history
+                Some(current) if *current != conversation_id => return,
+                Some(_) => {}
+                // Chatting in the server's default conversation: adopt its id
+                None => app.conversation_id = Some(conversation_id),
+            }
+            if cancelled {
+                if app.streaming.as_ref().map(|(id, _)| id) == Some(&request_id) {
+                    app.streaming = None;
+                }
+                app.messages.push(ChatEntry::System("Reply cancelled".into()));
+                return;
+            }
+            // Streaming: append to the entry of this request or create a new one
+            match app.streaming {
+                Some((ref id, idx)) if *id == request_id => {
+                    if let Some(ChatEntry::Bot(s)) = app.messages.get_mut(idx) {
+                        s.push_str(&content);
+                    }
+                }
+                _ => {
                     app.messages.push(ChatEntry::Bot(content));
+                    app.streaming = Some((request_id, app.messages.len() - 1));
                 }
             }
+            if done {
+                app.streaming = None;
+            }
         }
-        ServerMessage::Error { message } => {
+        ServerMessage::Error { request_id, message, .. } => {
+            if request_id.is_some() && app.streaming.as_ref().map(|(id, _)| id) == request_id.as_ref() {
+                app.streaming = None;
+            }
+            if request_id.is_some() && app.in_flight == request_id {
+                app.in_flight = None;
+            }
             app.messages.push(ChatEntry::System(format!("Error: {message}")));
         }
-        ServerMessage::Status { gateway_connected } => {
+        ServerMessage::Retrying { request_id, attempt } => {
+            // The reply restarts from scratch, so drop what was streamed so far
+            if let Some((id, idx)) = app.streaming.take() {
+                if id == request_id {
+                    app.messages.remove(idx);
+                } else {
+                    app.streaming = Some((id, idx));
+                }
+            }
+            app.messages.push(ChatEntry::System(format!(
+                "Gateway lost, request will be retried (attempt {attempt})"
+            )));
+        }
+        ServerMessage::Status { gateway_connected, gateway_rtt_ms, relay_reachable, agent_attached } => {
+            let (before, _) = gateway_state(app);
             app.gateway_connected = gateway_connected;
-            let status = if gateway_connected { "Gateway connected" } else { "Gateway disconnected" };
-            app.messages.push(ChatEntry::System(status.into()));
+            app.gateway_rtt_ms = gateway_rtt_ms;
+            app.relay_reachable = relay_reachable;
+            app.agent_attached = agent_attached;
+            // Also sent as the gateway RTT is updated; only report changes
+            let (after, notice) = gateway_state(app);
+            if after != before {
+                app.messages.push(ChatEntry::System(notice.into()));
+            }
+        }
+        ServerMessage::Authenticated { user_id } => {
+            app.messages.push(ChatEntry::System(format!("Signed in as {user_id}")));
+            app.user_id = Some(user_id);
+            let _ = app.outbound_tx.send(ClientMessage::ListConversations).await;
+        }
+        ServerMessage::Conversations { conversations } => {
+            app.conversations = conversations;
+            let latest = app.conversations.first().map(|c| c.conversation_id.clone());
+            match app.conversation_id.clone() {
+                // Resume the most recent conversation on first sign-in
+                None => {
+                    if let Some(latest) = latest {
+                        open_conversation(app, latest).await;
+                    }
+                }
+                // The shown conversation was deleted
+                Some(current) if !app.conversations.iter().any(|c| c.conversation_id == current) => {
+                    match latest {
+                        Some(latest) => switch_conversation(app, latest).await,
+                        None => {
+                            app.conversation_id = None;
+                            app.messages.clear();
+                            app.messages.push(ChatEntry::System("Conversation deleted".into()));
+                        }
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        ServerMessage::ConversationCreated { conversation } => {
+            let conversation_id = conversation.conversation_id.clone();
+            app.conversations.insert(0, conversation);
+            switch_conversation(app, conversation_id).await;
+        }
+        ServerMessage::History { conversation_id, entries, has_more } => {
+            if app.conversation_id.as_deref() != Some(conversation_id.as_str()) {
+                return;
+            }
+            let Some(first) = entries.first() else {
+                return;
+            };
+            app.history_cursor = Some(first.seq);
+            app.history_has_more = has_more;
+            if let Some((_, idx)) = app.streaming.as_mut() {
+                *idx += entries.len();
+            }
+            let restored = entries.into_iter().map(|e| match e.role {
+                Role::User => ChatEntry::User(e.content),
+                Role::Bot => ChatEntry::Bot(e.content),
+            });
+            // Below the banner (welcome, sign-in, conversation) and above anything said since
+            let at = app
+                .messages
+                .iter()
+                .position(|m| !matches!(m, ChatEntry::System(_)))
+                .unwrap_or(app.messages.len());
+            app.messages.splice(at..at, restored);
         }
-        ServerMessage::Pong => {}
+        // Latency is measured by the ws task
+        ServerMessage::Pong { .. } => {}
+        // Version negotiation is handled by the ws task
+        ServerMessage::Hello { .. } => {}
+    }
+}
+
+/// Status bar label and notice for how far the server reaches towards the gateway
+fn gateway_state(app: &App) -> (&'static str, &'static str) {
+    if app.gateway_connected {
+        ("CONNECTED", "Gateway connected")
+    } else if app.relay_reachable == Some(false) {
+        ("RELAY DOWN", "Server cannot reach the relay")
+    } else if app.agent_attached == Some(false) {
+        ("NO AGENT", "No agent attached to the relay")
+    } else {
+        ("DISCONNECTED", "Gateway disconnected")
     }
 }
 
@@ -120,9 +478,29 @@ fn draw(f: &mut Frame, app: &App) {
         .split(f.area());
 
     // Status bar
-    let status = if app.gateway_connected { "CONNECTED" } else { "DISCONNECTED" };
-    let status_line = Paragraph::new(format!(" MyClaw | Gateway: {status} | Ctrl+C to quit"))
-        .style(Style::default().bg(Color::Blue).fg(Color::White));
+    let (server, bg) = match app.link {
+        Link::Connecting => ("CONNECTING", Color::Blue),
+        Link::Online => ("ONLINE", Color::Blue),
+        Link::Reconnecting => ("RECONNECTING", Color::Red),
+        Link::Stopped => ("OFFLINE", Color::Red),
+    };
+    let (state, _) = gateway_state(app);
+    let status = match app.gateway_rtt_ms {
+        Some(ms) if app.gateway_connected => format!("{state} {ms}ms"),
+        _ => state.to_string(),
+    };
+    let server = match app.latency_ms {
+        Some(ms) if matches!(app.link, Link::Online) => format!("{server} {ms}ms"),
+        _ => server.to_string(),
+    };
+    let user = app.user_id.as_deref().unwrap_or("-");
+    let conversation = app
+        .conversation_id
+        .as_ref()
+        .and_then(|id| app.conversations.iter().find(|c| c.conversation_id == *id))
+        .map_or_else(|| "-".to_string(), conversation_label);
+    let status_line = Paragraph::new(format!(" MyClaw | Server: {server} | User: {user} | Chat: {conversation} | Gateway: {status} | Esc cancel | PgUp older | Ctrl+C to quit"))
+        .style(Style::default().bg(bg).fg(Color::White));
     f.render_widget(status_line, chunks[0]);
 
     // Chat messages
@@ -1,33 +1,178 @@
+use std::collections::VecDeque;
+
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, StreamExt};
+use myclaw_common::protocol::{Capability, Decoder, Encoder, ErrorCode, PROTOCOL_VERSION};
 use myclaw_common::{ClientMessage, ServerMessage};
 use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, info, warn};
+use chrono::Utc;
+use tokio::time::{interval, sleep, Duration};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::{debug, error, info, warn};
+
+use crate::config::ServerAddr;
+
+/// Features the TUI uses when the server has them
+const CAPABILITIES: [Capability; 2] = [Capability::Cancel, Capability::Conversations];
+
+/// What the WebSocket task reports to the TUI.
+pub enum WsEvent {
+    /// Socket (re)established; queued messages have been flushed
+    Connected,
+    /// Socket dropped; next attempt after `delay_ms`
+    Reconnecting { delay_ms: u64, queued: usize },
+    /// Gave up for good, e.g. the server refused our token
+    Stopped(String),
+    /// Round trip of the latest `ping`, in milliseconds
+    Latency(u64),
+    Message(ServerMessage),
+}
 
-/// Run the WebSocket connection loop.
-/// - `outbound_rx`: messages from TUI to send to server
-/// - `inbound_tx`: messages from server to forward to TUI
+/// How a single connection ended.
+enum Exit {
+    /// Socket closed or failed; reconnect
+    Dropped,
+    /// TUI went away; stop
+    TuiClosed,
+    /// Server refused this client's protocol version; reconnecting will not help
+    Rejected(String),
+}
+
+/// Run the WebSocket connection loop, reconnecting with exponential backoff.
+/// - `outbound_rx`: messages from TUI to send to server; queued while offline
+/// - `inbound_tx`: server messages and connection state for the TUI
 pub async fn run(
-    url: &str,
+    server: &ServerAddr,
     mut outbound_rx: mpsc::Receiver<ClientMessage>,
-    inbound_tx: mpsc::Sender<ServerMessage>,
+    inbound_tx: mpsc::Sender<WsEvent>,
 ) -> Result<()> {
-    info!("Connecting to server: {url}");
-    let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+    let mut queue: VecDeque<ClientMessage> = VecDeque::new();
+    let mut backoff_ms = server.reconnect_base_ms;
+
+    loop {
+        let mut encoder = Encoder::default();
+        let exit = connect_and_run(
+            server,
+            &mut queue,
+            &mut outbound_rx,
+            &inbound_tx,
+            &mut backoff_ms,
+            &mut encoder,
+        )
+        .await;
+        if encoder.stats().raw_bytes > 0 {
+            info!("Server link {}", encoder.stats());
+        }
+        match exit {
+            Ok(Exit::TuiClosed) => {
+                debug!("TUI closed, stopping WS");
+                return Ok(());
+            }
+            Ok(Exit::Dropped) => info!("Server connection closed"),
+            Ok(Exit::Rejected(reason)) => {
+                let _ = inbound_tx.send(WsEvent::Stopped(reason.clone())).await;
+                anyhow::bail!("server rejected client: {reason}");
+            }
+            Err(e) => {
+                if let Some(tungstenite::Error::Http(resp)) = e.downcast_ref::<tungstenite::Error>() {
+                    if resp.status() == StatusCode::UNAUTHORIZED {
+                        let _ = inbound_tx
+                            .send(WsEvent::Stopped("server rejected token".into()))
+                            .await;
+                        anyhow::bail!("server rejected token");
+                    }
+                }
+                error!("Server connection error: {e}");
+            }
+        }
+
+        warn!("Reconnecting to server in {backoff_ms}ms...");
+        let event = WsEvent::Reconnecting { delay_ms: backoff_ms, queued: queue.len() };
+        if inbound_tx.send(event).await.is_err() {
+            return Ok(());
+        }
+
+        // Keep accepting input while waiting so the TUI never blocks on a full channel
+        let delay = sleep(Duration::from_millis(backoff_ms));
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                _ = &mut delay => break,
+                msg = outbound_rx.recv() => match msg {
+                    Some(msg) => queue.push_back(msg),
+                    None => return Ok(()),
+                },
+            }
+        }
+        backoff_ms = (backoff_ms * 2).min(server.reconnect_max_ms);
+    }
+}
+
+async fn connect_and_run(
+    server: &ServerAddr,
+    queue: &mut VecDeque<ClientMessage>,
+    outbound_rx: &mut mpsc::Receiver<ClientMessage>,
+    inbound_tx: &mpsc::Sender<WsEvent>,
+    backoff_ms: &mut u64,
+    encoder: &mut Encoder,
+) -> Result<Exit> {
+    info!("Connecting to server: {}", server.url);
+    let mut request = server.url.as_str().into_client_request()?;
+    request
+        .headers_mut()
+        .insert(AUTHORIZATION, format!("Bearer {}", server.token).parse()?);
+    let (ws, _) = tokio_tungstenite::connect_async(request).await?;
     let (mut sink, mut stream) = ws.split();
     info!("Connected to server");
+    *backoff_ms = server.reconnect_base_ms;
+
+    // Plain JSON until the server's hello confirms the configured codec and compression
+    let mut capabilities = CAPABILITIES.to_vec();
+    capabilities.extend(server.codec.capabilities());
+    if server.compress {
+        capabilities.push(Capability::Deflate);
+    }
+    let hello = ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities,
+    };
+    sink.send(encoder.encode(&hello)?).await?;
+    flush(&mut sink, queue, encoder).await?;
+    if inbound_tx.send(WsEvent::Connected).await.is_err() {
+        return Ok(Exit::TuiClosed);
+    }
+
+    // Fires at once, so the latency is known right after connecting
+    let mut pinger = interval(Duration::from_secs(server.ping_interval_secs.max(1)));
+    // Compressed replies are only accepted once the server's hello agreed to them
+    let mut decoder = Decoder::default();
 
     loop {
         tokio::select! {
             msg = stream.next() => {
                 match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        match serde_json::from_str::<ServerMessage>(&text) {
+                    Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                        match decoder.decode::<ServerMessage>(&msg) {
+                            Ok(ServerMessage::Hello { version, capabilities }) => {
+                                info!("Server speaks protocol v{version}, shared capabilities: {capabilities:?}");
+                                encoder.negotiate(&capabilities);
+                                decoder.negotiate(&capabilities);
+                            }
+                            Ok(ServerMessage::Error { code: Some(ErrorCode::UnsupportedVersion), message, .. }) => {
+                                return Ok(Exit::Rejected(message));
+                            }
+                            Ok(ServerMessage::Pong { timestamp: Some(sent) }) => {
+                                let rtt = (Utc::now().timestamp_millis() - sent).max(0) as u64;
+                                if inbound_tx.send(WsEvent::Latency(rtt)).await.is_err() {
+                                    return Ok(Exit::TuiClosed);
+                                }
+                            }
                             Ok(server_msg) => {
-                                if inbound_tx.send(server_msg).await.is_err() {
-                                    debug!("TUI closed, stopping WS");
-                                    break;
+                                if inbound_tx.send(WsEvent::Message(server_msg)).await.is_err() {
+                                    return Ok(Exit::TuiClosed);
                                 }
                             }
                             Err(e) => warn!("Bad server msg: {e}"),
@@ -36,20 +181,37 @@ pub async fn run(
                     Some(Ok(Message::Ping(data))) => {
                         sink.send(Message::Pong(data)).await?;
                     }
-                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(Message::Close(_))) | None => return Ok(Exit::Dropped),
                     Some(Err(e)) => {
                         warn!("WS error: {e}");
-                        break;
+                        return Ok(Exit::Dropped);
                     }
                     _ => {}
                 }
             }
-            Some(client_msg) = outbound_rx.recv() => {
-                let json = serde_json::to_string(&client_msg)?;
-                sink.send(Message::Text(json)).await?;
+            client_msg = outbound_rx.recv() => {
+                let Some(client_msg) = client_msg else {
+                    return Ok(Exit::TuiClosed);
+                };
+                queue.push_back(client_msg);
+                flush(&mut sink, queue, encoder).await?;
+            }
+            // Probes are not queued: one sent while offline would measure nothing
+            _ = pinger.tick() => {
+                sink.send(encoder.encode(&ClientMessage::ping_now())?).await?;
             }
         }
     }
+}
 
+/// Send queued messages in order; a message leaves the queue only once written.
+async fn flush<S>(sink: &mut S, queue: &mut VecDeque<ClientMessage>, encoder: &mut Encoder) -> Result<()>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    while let Some(msg) = queue.front() {
+        sink.send(encoder.encode(msg)?).await?;
+        queue.pop_front();
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,9 @@
+/// Compare a presented key with a configured one without leaking
+/// the position of the first mismatch through timing.
+pub fn key_eq(presented: &str, expected: &str) -> bool {
+    let (a, b) = (presented.as_bytes(), expected.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use metrics::Counter;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::MyClawError;
+
+/// First byte of a compressed message: a raw deflate stream of a Text message follows.
+/// Neither marker can start a MessagePack map, so uncompressed Binary frames stay unambiguous.
+const DEFLATED_TEXT: u8 = 0x01;
+/// First byte of a compressed message: a raw deflate stream of a Binary message follows.
+const DEFLATED_BINARY: u8 = 0x02;
+
+/// Messages shorter than this are sent as they are; deflate rarely wins on them.
+pub const MIN_COMPRESS_BYTES: usize = 256;
+
+/// Largest message `inflate` will restore, matching tungstenite's default message limit.
+const MAX_INFLATED_BYTES: u64 = 64 << 20;
+
+/// Whether `msg` is a compressed message, which only `inflate` can read.
+pub fn is_deflated(msg: &Message) -> bool {
+    matches!(msg, Message::Binary(data) if matches!(data.first(), Some(&(DEFLATED_TEXT | DEFLATED_BINARY))))
+}
+
+/// Restore a compressed message to the Text or Binary message it was;
+/// any other message is returned unchanged.
+pub fn inflate(msg: Message) -> Result<Message, MyClawError> {
+    if !is_deflated(&msg) {
+        return Ok(msg);
+    }
+    let data = msg.into_data();
+    let mut raw = Vec::new();
+    DeflateDecoder::new(&data[1..])
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut raw)?;
+    if raw.len() as u64 > MAX_INFLATED_BYTES {
+        return Err(MyClawError::Protocol("compressed message too large".into()));
+    }
+    match data[0] {
+        DEFLATED_TEXT => String::from_utf8(raw)
+            .map(Message::Text)
+            .map_err(|_| MyClawError::Protocol("compressed text is not UTF-8".into())),
+        _ => Ok(Message::Binary(raw)),
+    }
+}
+
+/// Compresses the outgoing data messages of one link once both ends have agreed to it,
+/// and counts the bytes saved.
+#[derive(Debug, Default)]
+pub struct Compressor {
+    enabled: bool,
+    stats: CompressionStats,
+    counters: Option<SentCounters>,
+}
+
+/// Counters on `/metrics` that the outgoing data bytes of a link are added to,
+/// before and after compression, so the saving of long-lived links is visible.
+#[derive(Debug, Clone)]
+pub struct SentCounters {
+    raw: Counter,
+    wire: Counter,
+}
+
+impl SentCounters {
+    /// `<prefix>_sent_raw_bytes_total` and `<prefix>_sent_wire_bytes_total`, labeled with the kind of `link`
+    pub fn new(prefix: &str, link: &'static str) -> Self {
+        Self {
+            raw: metrics::counter!(format!("{prefix}_sent_raw_bytes_total"), "link" => link),
+            wire: metrics::counter!(format!("{prefix}_sent_wire_bytes_total"), "link" => link),
+        }
+    }
+}
+
+/// Outgoing data bytes of a link, before and after compression.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub raw_bytes: u64,
+    pub wire_bytes: u64,
+}
+
+impl Compressor {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            stats: CompressionStats::default(),
+            counters: None,
+        }
+    }
+
+    /// Also add the bytes sent to `counters`
+    pub fn counted(mut self, counters: SentCounters) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    pub fn enable(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Compress a Text or Binary message when enabled and worth it; other messages,
+    /// short ones and ones deflate cannot shrink are passed through.
+    pub fn compress(&mut self, msg: Message) -> Message {
+        let (marker, raw) = match &msg {
+            Message::Text(text) => (DEFLATED_TEXT, text.as_bytes()),
+            Message::Binary(data) => (DEFLATED_BINARY, data.as_slice()),
+            _ => return msg,
+        };
+        let raw_len = raw.len();
+        self.stats.raw_bytes += raw_len as u64;
+        let compressed = (self.enabled && raw.len() >= MIN_COMPRESS_BYTES)
+            .then(|| deflate(marker, raw))
+            .flatten()
+            .filter(|compressed| compressed.len() < raw.len());
+        let msg = compressed.map(Message::Binary).unwrap_or(msg);
+        self.stats.wire_bytes += msg.len() as u64;
+        if let Some(counters) = &self.counters {
+            counters.raw.increment(raw_len as u64);
+            counters.wire.increment(msg.len() as u64);
+        }
+        msg
+    }
+}
+
+fn deflate(marker: u8, raw: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![marker], Compression::fast());
+    encoder.write_all(raw).ok()?;
+    encoder.finish().ok()
+}
+
+impl CompressionStats {
+    /// Bytes compression kept off the wire.
+    pub fn saved_bytes(&self) -> u64 {
+        self.raw_bytes.saturating_sub(self.wire_bytes)
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = if self.raw_bytes == 0 {
+            0.0
+        } else {
+            self.saved_bytes() as f64 * 100.0 / self.raw_bytes as f64
+        };
+        write!(
+            f,
+            "sent {} bytes as {} ({:.1}% saved)",
+            self.raw_bytes, self.wire_bytes, percent
+        )
+    }
+}
@@ -8,6 +8,7 @@ pub enum MyClawError {
     Gateway(String),
     Io(std::io::Error),
     Json(serde_json::Error),
+    Msgpack(String),
 }
 
 impl fmt::Display for MyClawError {
@@ -19,6 +20,7 @@ impl fmt::Display for MyClawError {
             Self::Gateway(msg) => write!(f, "Gateway error: {msg}"),
             Self::Io(e) => write!(f, "IO error: {e}"),
             Self::Json(e) => write!(f, "JSON error: {e}"),
+            Self::Msgpack(msg) => write!(f, "MessagePack error: {msg}"),
         }
     }
 }
@@ -36,3 +38,15 @@ impl From<serde_json::Error> for MyClawError {
         Self::Json(e)
     }
 }
+
+impl From<rmp_serde::encode::Error> for MyClawError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        Self::Msgpack(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for MyClawError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        Self::Msgpack(e.to_string())
+    }
+}
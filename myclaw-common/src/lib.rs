@@ -1,5 +1,9 @@
+pub mod auth;
+pub mod compress;
 pub mod error;
 pub mod protocol;
+pub mod tls;
+pub mod trace;
 
 pub use error::MyClawError;
-pub use protocol::{ClientMessage, GatewayFrame, RelayFrame, ServerMessage};
+pub use protocol::{ClientMessage, GatewayFrame, RejectCode, RelayFrame, ServerMessage};
@@ -1,34 +1,367 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
 use uuid::Uuid;
 
+use crate::compress::{self, CompressionStats, Compressor, SentCounters};
+use crate::trace::TraceContext;
+use crate::MyClawError;
+
+/// Protocol version of this build. Version 1 is the protocol from before handshakes
+/// carried a version, so a missing `version` field means 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version accepted on relay links: version 1 agents and servers predate
+/// relay authentication and `forward` framing.
+pub const MIN_RELAY_VERSION: u32 = 2;
+
+/// Oldest version accepted from clients and the gateway, which have downgrade paths.
+pub const MIN_PEER_VERSION: u32 = 1;
+
+/// Optional features a peer announces in its handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Understands `cancel` frames
+    Cancel,
+    /// Keeps a separate context per `context_id` within a session
+    Contexts,
+    /// Supports multiple named conversations per user
+    Conversations,
+    /// Accepts MessagePack frames in WebSocket Binary messages
+    Msgpack,
+    /// Accepts deflate-compressed messages (see `compress`)
+    Deflate,
+    /// Announced by a newer peer and unknown to this build
+    #[serde(other)]
+    Unknown,
+}
+
+/// The version both ends of a link speak: the lower of the two, if at least `min`.
+pub fn negotiate_version(peer: u32, min: u32) -> Option<u32> {
+    let version = peer.min(PROTOCOL_VERSION);
+    (version >= min).then_some(version)
+}
+
+/// Capabilities announced by both ends.
+pub fn common_capabilities(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .filter(|c| **c != Capability::Unknown && theirs.contains(c))
+        .copied()
+        .collect()
+}
+
+/// Wire encoding of frames sent after the handshake. Handshake frames are always JSON,
+/// and receivers decode by message type, so each end switches as soon as it has negotiated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// JSON in WebSocket Text messages
+    #[default]
+    Json,
+    /// MessagePack in WebSocket Binary messages
+    Msgpack,
+}
+
+impl Codec {
+    /// Capabilities the connecting end announces to ask for this codec.
+    pub fn capabilities(self) -> Vec<Capability> {
+        match self {
+            Self::Json => Vec::new(),
+            Self::Msgpack => vec![Capability::Msgpack],
+        }
+    }
+
+    /// The codec to send with, given the capabilities both ends share.
+    pub fn negotiated(shared: &[Capability]) -> Self {
+        if shared.contains(&Capability::Msgpack) {
+            Self::Msgpack
+        } else {
+            Self::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, frame: &T) -> Result<Message, MyClawError> {
+        match self {
+            Self::Json => Ok(Message::Text(serde_json::to_string(frame)?)),
+            // Named fields, so internally tagged enums and skipped fields decode
+            Self::Msgpack => Ok(Message::Binary(rmp_serde::to_vec_named(frame)?)),
+        }
+    }
+}
+
+/// Encodes what one end of a link sends after the handshake: the negotiated codec,
+/// then compression if both ends agreed to it.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    codec: Codec,
+    compressor: Compressor,
+}
+
+impl Encoder {
+    /// A JSON encoder whose bytes sent are also added to `counters`
+    pub fn counted(counters: SentCounters) -> Self {
+        Self {
+            codec: Codec::default(),
+            compressor: Compressor::default().counted(counters),
+        }
+    }
+
+    /// Switch to the codec and compression the capabilities both ends share ask for.
+    pub fn negotiate(&mut self, shared: &[Capability]) {
+        self.codec = Codec::negotiated(shared);
+        self.compressor.enable(shared.contains(&Capability::Deflate));
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn encode<T: Serialize>(&mut self, frame: &T) -> Result<Message, MyClawError> {
+        Ok(self.compressor.compress(self.codec.encode(frame)?))
+    }
+
+    /// Bytes sent so far, before and after compression
+    pub fn stats(&self) -> CompressionStats {
+        self.compressor.stats()
+    }
+}
+
+/// Decodes what one end of a link receives after the handshake, inflating compressed
+/// messages only once both ends agreed to deflate.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Decoder {
+    inflate: bool,
+}
+
+impl Decoder {
+    /// Accept compressed messages if the capabilities both ends share include deflate.
+    pub fn negotiate(&mut self, shared: &[Capability]) {
+        self.inflate = shared.contains(&Capability::Deflate);
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, msg: &Message) -> Result<T, MyClawError> {
+        if self.inflate && compress::is_deflated(msg) {
+            decode(&compress::inflate(msg.clone())?)
+        } else {
+            decode(msg)
+        }
+    }
+}
+
+/// Decode a Text message as JSON or a Binary message as MessagePack, whichever codec
+/// the peer chose. Compressed messages are refused; see [`Decoder`].
+pub fn decode<T: DeserializeOwned>(msg: &Message) -> Result<T, MyClawError> {
+    match msg {
+        Message::Text(text) => Ok(serde_json::from_str(text)?),
+        Message::Binary(_) if compress::is_deflated(msg) => {
+            Err(MyClawError::Protocol("compressed message on a link without deflate".into()))
+        }
+        Message::Binary(data) => Ok(rmp_serde::from_slice(data)?),
+        other => Err(MyClawError::Protocol(format!("not a data message: {other:?}"))),
+    }
+}
+
+fn version_1() -> u32 {
+    1
+}
+
 /// Client → Server messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
+    /// First frame of a connection: the client's protocol version and capabilities
+    #[serde(rename = "hello")]
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Authenticate when the bearer token was not sent in the upgrade headers
+    #[serde(rename = "auth")]
+    Auth { token: String },
     #[serde(rename = "chat")]
-    Chat { id: String, content: String },
+    Chat {
+        id: String,
+        content: String,
+        /// Conversation to chat in; the user's default conversation when absent
+        #[serde(default)]
+        conversation_id: Option<String>,
+        /// What to do with the request if the gateway link drops mid-reply
+        #[serde(default)]
+        on_disconnect: DisconnectPolicy,
+        /// Trace this message starts or continues, followed through server, relay and agent
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::trace::traceparent")]
+        traceparent: Option<TraceContext>,
+    },
+    /// Latency probe; `timestamp` (client clock, Unix milliseconds) is echoed in the `pong`
     #[serde(rename = "ping")]
-    Ping,
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+    },
+    /// Stop a reply that is still streaming
+    #[serde(rename = "cancel")]
+    Cancel { request_id: String },
+    /// Ask for the user's stored conversations
+    #[serde(rename = "list_conversations")]
+    ListConversations,
+    /// Start a new conversation with its own gateway context
+    #[serde(rename = "create_conversation")]
+    CreateConversation {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    #[serde(rename = "rename_conversation")]
+    RenameConversation { conversation_id: String, name: String },
+    /// Drop a conversation from the user's list; its messages stay in the log
+    #[serde(rename = "delete_conversation")]
+    DeleteConversation { conversation_id: String },
+    /// Ask for a page of a conversation, newest first from `before` (exclusive)
+    #[serde(rename = "load_history")]
+    LoadHistory {
+        conversation_id: String,
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
 }
 
 /// Server → Client messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
+    /// Reply to `hello`: the negotiated version and the capabilities both ends share
+    #[serde(rename = "hello")]
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
     #[serde(rename = "chat_reply")]
     ChatReply {
         id: String,
         request_id: String,
+        conversation_id: String,
         content: String,
         done: bool,
+        /// Final reply of a request the client cancelled
+        #[serde(default)]
+        cancelled: bool,
     },
+    /// `request_id` and `code` are set when the error ends a chat request
     #[serde(rename = "error")]
-    Error { message: String },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        message: String,
+    },
+    /// The gateway link dropped mid-reply; the request will be sent again once it is back,
+    /// and its reply restarts from the beginning
+    #[serde(rename = "retrying")]
+    Retrying { request_id: String, attempt: u32 },
+    /// Reply to `ping`, carrying its `timestamp` back
     #[serde(rename = "pong")]
-    Pong,
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<i64>,
+    },
+    /// State of the server's link to the gateway; sent after `authenticated` and on every change
     #[serde(rename = "status")]
-    Status { gateway_connected: bool },
+    Status {
+        /// Gateway handshake done, chat requests can be sent
+        gateway_connected: bool,
+        /// Round trip of the server's last heartbeat to the gateway, in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gateway_rtt_ms: Option<u64>,
+        /// Whether myclaw-relay accepts the server; absent when it reaches the gateway directly
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relay_reachable: Option<bool>,
+        /// Whether the relay attached the server to an agent; absent without a relay
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent_attached: Option<bool>,
+    },
+    /// Authentication succeeded
+    #[serde(rename = "authenticated")]
+    Authenticated { user_id: String },
+    /// Reply to `list_conversations`, `rename_conversation` and `delete_conversation`,
+    /// most recently active first
+    #[serde(rename = "conversations")]
+    Conversations {
+        conversations: Vec<ConversationSummary>,
+    },
+    /// Reply to `create_conversation`
+    #[serde(rename = "conversation_created")]
+    ConversationCreated { conversation: ConversationSummary },
+    /// Reply to `load_history`: entries in chronological order
+    #[serde(rename = "history")]
+    History {
+        conversation_id: String,
+        entries: Vec<HistoryEntry>,
+        /// Older entries exist before the first one returned
+        has_more: bool,
+    },
+}
+
+/// Per-request handling of a gateway disconnect
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectPolicy {
+    /// Fail the request with `ErrorCode::GatewayLost`
+    #[default]
+    Fail,
+    /// Resend the request once the gateway reconnects
+    Retry,
+}
+
+/// Why a chat request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The gateway was not connected when the request arrived
+    GatewayUnavailable,
+    /// The gateway link dropped before the reply finished
+    GatewayLost,
+    /// The conversation does not exist or was deleted
+    UnknownConversation,
+    /// The client's protocol version is not supported; the connection is closed
+    UnsupportedVersion,
+    /// The reply did not finish within the request deadline
+    DeadlineExceeded,
+    /// No reply chunk arrived within the idle timeout
+    IdleTimeout,
+}
+
+/// Author of a stored chat message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Bot,
+}
+
+/// One stored chat message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Position in the conversation, used as the `before` cursor
+    pub seq: u64,
+    pub request_id: String,
+    pub role: Role,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub conversation_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub message_count: u64,
+    pub updated_at: DateTime<Utc>,
 }
 
 /// Frames exchanged with OpenClaw Gateway (WebSocket :18789)
@@ -37,16 +370,35 @@ pub enum ServerMessage {
 pub enum GatewayFrame {
     /// Handshake: node connects to gateway
     #[serde(rename = "connect")]
-    Connect { role: String, node_id: String },
-    /// Gateway acknowledges connection
+    Connect {
+        role: String,
+        node_id: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Gateway acknowledges connection with its version and capabilities
     #[serde(rename = "connected")]
-    Connected { session_id: String },
+    Connected {
+        session_id: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
     /// Send a chat message to an agent
     #[serde(rename = "chat_request")]
     ChatRequest {
         request_id: String,
         session_id: String,
+        /// Conversation context within the session, so each conversation keeps its own history
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context_id: Option<String>,
         content: String,
+        /// The server's hop in the trace of the client's message
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::trace::traceparent")]
+        traceparent: Option<TraceContext>,
     },
     /// Streaming reply chunk from agent
     #[serde(rename = "chat_response")]
@@ -56,42 +408,200 @@ pub enum GatewayFrame {
         content: String,
         done: bool,
     },
+    /// Stop generating the reply to a chat request
+    #[serde(rename = "cancel")]
+    Cancel {
+        request_id: String,
+        session_id: String,
+    },
     /// Heartbeat
     #[serde(rename = "ping")]
     Ping { timestamp: i64 },
     #[serde(rename = "pong")]
     Pong { timestamp: i64 },
-    /// Error from gateway
+    /// Error from the gateway, or from myclaw-relay on its behalf;
+    /// `request_id` and `code` are set when it ends a chat request
     #[serde(rename = "error")]
-    Error { message: String },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        message: String,
+    },
 }
 
-/// Relay ↔ Agent control frames
+/// Relay ↔ Agent / Server frames
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum RelayFrame {
     /// Agent registers with relay
     #[serde(rename = "agent_hello")]
-    AgentHello { agent_id: String },
-    /// Relay acknowledges agent
+    AgentHello {
+        agent_id: String,
+        #[serde(default)]
+        key: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Relay acknowledges agent with the negotiated version and shared capabilities
     #[serde(rename = "agent_welcome")]
-    AgentWelcome { agent_id: String },
+    AgentWelcome {
+        agent_id: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Server authenticates with relay
+    #[serde(rename = "server_hello")]
+    ServerHello {
+        key: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    /// Relay acknowledges server and names the agent it is attached to
+    #[serde(rename = "server_welcome")]
+    ServerWelcome {
+        server_id: String,
+        /// Empty while held
+        agent_id: String,
+        #[serde(default = "version_1")]
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+        /// No agent is connected yet; what the server sends is held until one is
+        #[serde(default)]
+        held: bool,
+    },
+    /// Relay refuses a handshake; the connection is closed afterwards
+    #[serde(rename = "rejected")]
+    Rejected { code: RejectCode, message: String },
+    /// Gateway traffic of one myclaw-server connection, tunnelled over the agent link
+    #[serde(rename = "forward")]
+    Forward {
+        server_id: String,
+        payload: String,
+        /// Hop of the sender in the trace of the request the payload belongs to
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::trace::traceparent")]
+        traceparent: Option<TraceContext>,
+    },
+    /// Like `forward`, for gateway traffic sent as WebSocket Binary messages
+    #[serde(rename = "forward_binary")]
+    ForwardBinary {
+        server_id: String,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::trace::traceparent")]
+        traceparent: Option<TraceContext>,
+    },
+    /// The server connection (relay → agent) or its gateway link (agent → relay) ended
+    #[serde(rename = "close")]
+    Close { server_id: String },
+}
+
+/// Why the relay refused a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectCode {
+    /// First frame was not the expected hello
+    BadHandshake,
+    /// Unknown peer or wrong key
+    Unauthorized,
+    /// Server credentials do not cover the requested agent
+    Forbidden,
+    /// None of the server's agents is connected
+    AgentUnavailable,
+    /// The peer's protocol version is older than `MIN_RELAY_VERSION`
+    UnsupportedVersion,
 }
 
 impl ClientMessage {
-    pub fn new_chat(content: impl Into<String>) -> Self {
+    pub fn new_chat(
+        content: impl Into<String>,
+        conversation_id: Option<String>,
+        on_disconnect: DisconnectPolicy,
+    ) -> Self {
         Self::Chat {
             id: Uuid::new_v4().to_string(),
             content: content.into(),
+            conversation_id,
+            on_disconnect,
+            traceparent: Some(TraceContext::new_root()),
+        }
+    }
+
+    pub fn ping_now() -> Self {
+        Self::Ping {
+            timestamp: Some(Utc::now().timestamp_millis()),
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Oldest protocol version whose clients can read this message. Version 1 clients
+    /// know only `chat_reply`, `error`, `pong` and `status`, and ignore fields added since.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Self::ChatReply { .. } | Self::Error { .. } | Self::Pong { .. } | Self::Status { .. } => 1,
+            Self::Hello { .. }
+            | Self::Retrying { .. }
+            | Self::Authenticated { .. }
+            | Self::Conversations { .. }
+            | Self::ConversationCreated { .. }
+            | Self::History { .. } => 2,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            request_id: None,
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn request_error(request_id: &str, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            request_id: Some(request_id.into()),
+            code: Some(code),
+            message: message.into(),
+        }
+    }
+}
+
+impl RelayFrame {
+    /// Wrap one gateway message of `server_id` for the agent link, keeping
+    /// its Text or Binary type. `None` for control messages.
+    pub fn forward(server_id: &str, msg: Message, traceparent: Option<TraceContext>) -> Option<Self> {
+        let server_id = server_id.to_string();
+        match msg {
+            Message::Text(payload) => Some(Self::Forward {
+                server_id,
+                payload,
+                traceparent,
+            }),
+            Message::Binary(payload) => Some(Self::ForwardBinary {
+                server_id,
+                payload,
+                traceparent,
+            }),
+            _ => None,
         }
     }
 }
 
 impl GatewayFrame {
-    pub fn connect(node_id: &str) -> Self {
+    pub fn connect(node_id: &str, capabilities: Vec<Capability>) -> Self {
         Self::Connect {
             role: "node".into(),
             node_id: node_id.into(),
+            version: PROTOCOL_VERSION,
+            capabilities,
         }
     }
 
@@ -104,12 +614,31 @@ impl GatewayFrame {
     pub fn chat_request(
         request_id: &str,
         session_id: &str,
+        context_id: Option<&str>,
         content: &str,
+        traceparent: Option<TraceContext>,
     ) -> Self {
         Self::ChatRequest {
             request_id: request_id.into(),
             session_id: session_id.into(),
+            context_id: context_id.map(Into::into),
             content: content.into(),
+            traceparent,
+        }
+    }
+
+    pub fn cancel(request_id: &str, session_id: &str) -> Self {
+        Self::Cancel {
+            request_id: request_id.into(),
+            session_id: session_id.into(),
+        }
+    }
+
+    pub fn request_error(request_id: &str, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            request_id: Some(request_id.into()),
+            code: Some(code),
+            message: message.into(),
         }
     }
 }
@@ -0,0 +1,92 @@
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::MaybeTlsStream;
+
+use crate::MyClawError;
+
+/// Stream handed to `accept_async` by a listener that may or may not terminate TLS.
+pub type ServerStream = MaybeTlsStream<TcpStream>;
+
+/// How long a connection may take to complete the TLS handshake before it is dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Optional TLS termination for a WebSocket listener (`wss://` when configured).
+#[derive(Clone)]
+pub struct Acceptor {
+    tls: Option<tokio_native_tls::TlsAcceptor>,
+    handshake_timeout: Duration,
+}
+
+impl Acceptor {
+    /// Plain `ws://` listener.
+    pub fn plain() -> Self {
+        Self {
+            tls: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Give up on TLS handshakes after `timeout` instead of `HANDSHAKE_TIMEOUT`.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Build from optional `tls_cert` / `tls_key` settings: both set → TLS, neither → plain.
+    pub fn from_config(cert: Option<&Path>, key: Option<&Path>) -> Result<Self, MyClawError> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                let cert = std::fs::read(cert)?;
+                let key = std::fs::read(key)?;
+                Self::from_pem(&cert, &key)
+            }
+            (None, None) => Ok(Self::plain()),
+            _ => Err(MyClawError::Config(
+                "tls_cert and tls_key must be set together".into(),
+            )),
+        }
+    }
+
+    /// TLS listener from a PEM certificate chain and PKCS#8 PEM private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Self, MyClawError> {
+        let identity = native_tls::Identity::from_pkcs8(cert, key)
+            .map_err(|e| MyClawError::Config(format!("invalid TLS certificate or key: {e}")))?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)
+            .map_err(|e| MyClawError::Config(format!("TLS setup failed: {e}")))?;
+        Ok(Self {
+            tls: Some(acceptor.into()),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// URL scheme clients should use for this listener.
+    pub fn scheme(&self) -> &'static str {
+        if self.is_tls() {
+            "wss"
+        } else {
+            "ws"
+        }
+    }
+
+    /// Run the TLS handshake on an accepted connection, if TLS is enabled;
+    /// a peer that stalls it past the handshake timeout is dropped.
+    pub async fn accept(&self, stream: TcpStream) -> Result<ServerStream, MyClawError> {
+        match &self.tls {
+            Some(tls) => {
+                let stream = timeout(self.handshake_timeout, tls.accept(stream))
+                    .await
+                    .map_err(|_| MyClawError::WebSocket("TLS handshake timed out".into()))?
+                    .map_err(|e| MyClawError::WebSocket(format!("TLS handshake failed: {e}")))?;
+                Ok(MaybeTlsStream::NativeTls(stream))
+            }
+            None => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+}
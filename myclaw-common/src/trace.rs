@@ -0,0 +1,216 @@
+use std::fmt;
+
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::field;
+use tracing::Span;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::protocol::decode;
+
+/// Where a chat request is in its trace: the trace it belongs to and the span
+/// of the hop that sent it on. Written as a W3C `traceparent` on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+impl TraceContext {
+    /// Start a new trace, for a request that arrived without one
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: Uuid::new_v4().as_u128(),
+            span_id: new_span_id(),
+        }
+    }
+
+    /// The next hop in the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: new_span_id(),
+        }
+    }
+
+    /// Read `00-<trace_id>-<span_id>-<flags>`; `None` if malformed or all-zero.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version.len() != 2 || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let span_id = u64::from_str_radix(span_id, 16).ok()?;
+        (trace_id != 0 && span_id != 0).then_some(Self { trace_id, span_id })
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+fn new_span_id() -> u64 {
+    // Never zero, which `traceparent` reserves for "no span"
+    Uuid::new_v4().as_u64_pair().0 | 1
+}
+
+/// `traceparent` fields of frames: written as a W3C string, and read leniently,
+/// so a peer's malformed value drops the trace instead of the frame.
+pub(crate) mod traceparent {
+    use serde::de::IgnoredAny;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::TraceContext;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Text(String),
+        Other(IgnoredAny),
+    }
+
+    pub fn serialize<S: Serializer>(trace: &Option<TraceContext>, serializer: S) -> Result<S::Ok, S::Error> {
+        match trace {
+            Some(trace) => serializer.collect_str(trace),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<TraceContext>, D::Error> {
+        Ok(match Option::<Raw>::deserialize(deserializer)? {
+            Some(Raw::Text(value)) => TraceContext::parse(&value),
+            _ => None,
+        })
+    }
+}
+
+/// What the relay and agent read of a gateway message they forward: enough to
+/// log and trace it without decoding the rest.
+#[derive(Debug, Deserialize)]
+pub struct FrameHead {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub done: bool,
+    #[serde(default, with = "traceparent")]
+    pub traceparent: Option<TraceContext>,
+}
+
+impl FrameHead {
+    pub fn peek(msg: &Message) -> Option<Self> {
+        decode(msg).ok()
+    }
+
+    /// Request id of a frame worth a span of its own: a `chat_request`, or the
+    /// `chat_response` chunk or `error` that ends its reply.
+    pub fn traced(&self) -> Option<&str> {
+        match self.kind.as_str() {
+            "chat_request" | "error" => self.request_id.as_deref(),
+            "chat_response" if self.done => self.request_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Tie `span`, which must declare an empty `trace_id` field, to the trace of `parent`
+/// (a new trace without one), and return the context the next hop continues from.
+pub fn hop(span: &Span, parent: Option<TraceContext>) -> TraceContext {
+    let trace = exported(span, parent)
+        .unwrap_or_else(|| parent.map_or_else(TraceContext::new_root, |parent| parent.child()));
+    span.record("trace_id", field::display(format_args!("{:032x}", trace.trace_id)));
+    trace
+}
+
+/// The context OpenTelemetry gave `span` under `parent`, if spans are exported
+#[cfg(feature = "otlp")]
+fn exported(span: &Span, parent: Option<TraceContext>) -> Option<TraceContext> {
+    otlp::link(span, parent)
+}
+
+#[cfg(not(feature = "otlp"))]
+fn exported(_span: &Span, _parent: Option<TraceContext>) -> Option<TraceContext> {
+    None
+}
+
+/// Install the log subscriber with `filter`, also exporting spans as `service` to
+/// the OTLP/gRPC collector at `otlp_endpoint` if one is set (needs the `otlp` feature).
+pub fn init(filter: &str, service: &'static str, otlp_endpoint: Option<&str>) -> anyhow::Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::new(filter))
+        .with(tracing_subscriber::fmt::layer());
+    match otlp_endpoint {
+        #[cfg(feature = "otlp")]
+        Some(endpoint) => {
+            registry.with(otlp::layer(service, endpoint)?).init();
+            tracing::info!("Exporting spans to {endpoint}");
+        }
+        #[cfg(not(feature = "otlp"))]
+        Some(_) => {
+            registry.init();
+            tracing::warn!("{service} was built without the otlp feature; not exporting spans");
+        }
+        None => registry.init(),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider as _,
+    };
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing::{Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use super::TraceContext;
+
+    pub fn layer<S>(service: &'static str, endpoint: &str) -> anyhow::Result<impl Layer<S>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", service)]))
+            .build();
+        let tracer = provider.tracer(service);
+        opentelemetry::global::set_tracer_provider(provider);
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    /// Make `parent` the OpenTelemetry parent of `span` and return the span's own
+    /// context; `None` when the span is not exported.
+    pub fn link(span: &Span, parent: Option<TraceContext>) -> Option<TraceContext> {
+        if let Some(parent) = parent {
+            let remote = SpanContext::new(
+                TraceId::from_bytes(parent.trace_id.to_be_bytes()),
+                SpanId::from_bytes(parent.span_id.to_be_bytes()),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            );
+            span.set_parent(opentelemetry::Context::new().with_remote_span_context(remote));
+        }
+        let context = span.context();
+        let own = context.span().span_context().clone();
+        own.is_valid().then(|| TraceContext {
+            trace_id: u128::from_be_bytes(own.trace_id().to_bytes()),
+            span_id: u64::from_be_bytes(own.span_id().to_bytes()),
+        })
+    }
+}
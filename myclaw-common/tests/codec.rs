@@ -0,0 +1,169 @@
+use chrono::Utc;
+use myclaw_common::protocol::{
+    decode, Capability, Codec, ConversationSummary, DisconnectPolicy, ErrorCode, HistoryEntry,
+    Role,
+};
+use myclaw_common::trace::{FrameHead, TraceContext};
+use myclaw_common::{ClientMessage, GatewayFrame, RelayFrame, ServerMessage};
+use tokio_tungstenite::tungstenite::Message;
+
+#[test]
+fn codecs_pick_their_message_type() {
+    let frame = GatewayFrame::ping_now();
+    assert!(matches!(Codec::Json.encode(&frame).unwrap(), Message::Text(_)));
+    assert!(matches!(Codec::Msgpack.encode(&frame).unwrap(), Message::Binary(_)));
+}
+
+#[test]
+fn client_messages_round_trip_through_msgpack() {
+    let messages = [
+        ClientMessage::Hello {
+            version: 2,
+            capabilities: vec![Capability::Msgpack],
+        },
+        ClientMessage::new_chat("hi", Some("c1".into()), DisconnectPolicy::Retry),
+        ClientMessage::ping_now(),
+        ClientMessage::CreateConversation { name: None },
+        ClientMessage::LoadHistory {
+            conversation_id: "default".into(),
+            before: Some(7),
+            limit: None,
+        },
+    ];
+    for msg in messages {
+        let wire = Codec::Msgpack.encode(&msg).unwrap();
+        let back: ClientMessage = decode(&wire).unwrap();
+        assert_eq!(format!("{back:?}"), format!("{msg:?}"));
+    }
+}
+
+#[test]
+fn server_messages_round_trip_through_msgpack() {
+    let summary = ConversationSummary {
+        conversation_id: "c1".into(),
+        name: Some("work".into()),
+        message_count: 3,
+        updated_at: Utc::now(),
+    };
+    let messages = [
+        ServerMessage::request_error("r1", ErrorCode::GatewayLost, "gone"),
+        ServerMessage::error("boom"),
+        ServerMessage::Pong { timestamp: Some(1_700_000_000_000) },
+        ServerMessage::Conversations {
+            conversations: vec![summary],
+        },
+        ServerMessage::History {
+            conversation_id: "c1".into(),
+            entries: vec![HistoryEntry {
+                seq: 1,
+                request_id: "r1".into(),
+                role: Role::Bot,
+                content: "héllo \"quoted\"\n".into(),
+                timestamp: Utc::now(),
+            }],
+            has_more: false,
+        },
+    ];
+    for msg in messages {
+        let wire = Codec::Msgpack.encode(&msg).unwrap();
+        let back: ServerMessage = decode(&wire).unwrap();
+        assert_eq!(format!("{back:?}"), format!("{msg:?}"));
+    }
+}
+
+#[test]
+fn gateway_frames_round_trip_through_msgpack() {
+    let frame = GatewayFrame::chat_request("r1", "s1", None, "hi", None);
+    let back: GatewayFrame = decode(&Codec::Msgpack.encode(&frame).unwrap()).unwrap();
+    assert!(matches!(back, GatewayFrame::ChatRequest { context_id: None, .. }));
+}
+
+#[test]
+fn text_and_binary_decode_regardless_of_our_codec() {
+    let frame = GatewayFrame::chat_request("r1", "s1", Some("c1"), "hi", None);
+    for codec in [Codec::Json, Codec::Msgpack] {
+        let back: GatewayFrame = decode(&codec.encode(&frame).unwrap()).unwrap();
+        assert!(matches!(back, GatewayFrame::ChatRequest { context_id: Some(_), .. }));
+    }
+    assert!(decode::<GatewayFrame>(&Message::Ping(Vec::new())).is_err());
+    assert!(decode::<GatewayFrame>(&Message::Binary(vec![0xc1])).is_err());
+}
+
+#[test]
+fn msgpack_is_used_only_when_shared() {
+    assert_eq!(Codec::negotiated(&[Capability::Cancel]), Codec::Json);
+    assert_eq!(Codec::negotiated(&[Capability::Msgpack]), Codec::Msgpack);
+    assert!(Codec::Json.capabilities().is_empty());
+    assert_eq!(Codec::Msgpack.capabilities(), vec![Capability::Msgpack]);
+}
+
+#[test]
+fn forward_keeps_the_gateway_message_type() {
+    let text = RelayFrame::forward("server-1", Message::Text("{}".into()), None).unwrap();
+    assert!(matches!(text, RelayFrame::Forward { .. }));
+
+    let binary = RelayFrame::forward("server-1", Message::Binary(vec![1, 2, 3]), None).unwrap();
+    for codec in [Codec::Json, Codec::Msgpack] {
+        let back: RelayFrame = decode(&codec.encode(&binary).unwrap()).unwrap();
+        let RelayFrame::ForwardBinary { payload, .. } = back else {
+            panic!("expected forward_binary");
+        };
+        assert_eq!(payload, vec![1, 2, 3]);
+    }
+    assert!(RelayFrame::forward("server-1", Message::Ping(Vec::new()), None).is_none());
+}
+
+#[test]
+fn msgpack_forward_does_not_escape_the_payload() {
+    let payload = serde_json::to_string(&GatewayFrame::chat_request("r1", "s1", None, "hi", None)).unwrap();
+    let frame = RelayFrame::Forward {
+        server_id: "server-1".into(),
+        payload: payload.clone(),
+        traceparent: None,
+    };
+    let json = Codec::Json.encode(&frame).unwrap();
+    let msgpack = Codec::Msgpack.encode(&frame).unwrap();
+    assert!(msgpack.len() < json.len());
+    assert!(msgpack.into_data().windows(payload.len()).any(|w| w == payload.as_bytes()));
+}
+
+#[test]
+fn trace_context_follows_a_request_through_both_codecs() {
+    let trace = TraceContext::new_root();
+    let hop = trace.child();
+    assert_eq!(hop.trace_id, trace.trace_id);
+    assert_ne!(hop.span_id, trace.span_id);
+    assert_eq!(TraceContext::parse(&trace.to_string()), Some(trace));
+
+    let request = GatewayFrame::chat_request("r1", "s1", None, "hi", Some(trace));
+    for codec in [Codec::Json, Codec::Msgpack] {
+        let msg = codec.encode(&request).unwrap();
+        let head = FrameHead::peek(&msg).unwrap();
+        assert_eq!(head.traced(), Some("r1"));
+        assert_eq!(head.traceparent, Some(trace));
+
+        let frame = RelayFrame::forward("server-1", msg, Some(hop)).unwrap();
+        let back: RelayFrame = decode(&codec.encode(&frame).unwrap()).unwrap();
+        let (RelayFrame::Forward { traceparent, .. } | RelayFrame::ForwardBinary { traceparent, .. }) = back else {
+            panic!("expected forward");
+        };
+        assert_eq!(traceparent, Some(hop));
+    }
+}
+
+#[test]
+fn only_request_and_final_reply_frames_are_traced() {
+    let chunk = |done| {
+        let json = format!(r#"{{"type":"chat_response","request_id":"r1","session_id":"s1","content":"","done":{done}}}"#);
+        FrameHead::peek(&Message::Text(json)).unwrap()
+    };
+    assert_eq!(chunk(false).traced(), None);
+    assert_eq!(chunk(true).traced(), Some("r1"));
+
+    let error = GatewayFrame::request_error("r1", ErrorCode::GatewayUnavailable, "gone");
+    let head = FrameHead::peek(&Codec::Json.encode(&error).unwrap()).unwrap();
+    assert_eq!(head.traced(), Some("r1"));
+
+    let ping = FrameHead::peek(&Codec::Json.encode(&GatewayFrame::ping_now()).unwrap()).unwrap();
+    assert_eq!(ping.traced(), None);
+}
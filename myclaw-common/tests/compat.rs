@@ -0,0 +1,176 @@
+use myclaw_common::protocol::{
+    common_capabilities, negotiate_version, Capability, DisconnectPolicy, ErrorCode,
+    MIN_PEER_VERSION, MIN_RELAY_VERSION, PROTOCOL_VERSION,
+};
+use myclaw_common::{ClientMessage, GatewayFrame, RelayFrame, ServerMessage};
+
+#[test]
+fn unversioned_handshakes_read_as_version_1() {
+    let connect: GatewayFrame =
+        serde_json::from_str(r#"{"type":"connect","role":"node","node_id":"n1"}"#).unwrap();
+    let GatewayFrame::Connect { version, capabilities, .. } = connect else {
+        panic!("expected connect");
+    };
+    assert_eq!(version, 1);
+    assert!(capabilities.is_empty());
+
+    let connected: GatewayFrame =
+        serde_json::from_str(r#"{"type":"connected","session_id":"s1"}"#).unwrap();
+    assert!(matches!(connected, GatewayFrame::Connected { version: 1, .. }));
+
+    let hello: RelayFrame =
+        serde_json::from_str(r#"{"type":"agent_hello","agent_id":"a1"}"#).unwrap();
+    assert!(matches!(hello, RelayFrame::AgentHello { version: 1, .. }));
+}
+
+#[test]
+fn relay_rejects_version_1_peers() {
+    assert_eq!(negotiate_version(1, MIN_RELAY_VERSION), None);
+    assert_eq!(negotiate_version(PROTOCOL_VERSION, MIN_RELAY_VERSION), Some(PROTOCOL_VERSION));
+}
+
+#[test]
+fn newer_peer_is_downgraded_to_our_version() {
+    assert_eq!(negotiate_version(PROTOCOL_VERSION + 5, MIN_PEER_VERSION), Some(PROTOCOL_VERSION));
+    assert_eq!(negotiate_version(1, MIN_PEER_VERSION), Some(1));
+    assert_eq!(negotiate_version(0, MIN_PEER_VERSION), None);
+}
+
+#[test]
+fn unknown_capabilities_and_fields_from_newer_peers_are_ignored() {
+    let json = r#"{"type":"connected","session_id":"s1","version":9,
+        "capabilities":["cancel","teleport"],"extra":true}"#;
+    let GatewayFrame::Connected { capabilities, .. } = serde_json::from_str(json).unwrap() else {
+        panic!("expected connected");
+    };
+    assert_eq!(capabilities, vec![Capability::Cancel, Capability::Unknown]);
+
+    let ours = [Capability::Cancel, Capability::Contexts];
+    assert_eq!(common_capabilities(&ours, &capabilities), vec![Capability::Cancel]);
+}
+
+#[test]
+fn version_1_client_messages_still_parse() {
+    let chat: ClientMessage =
+        serde_json::from_str(r#"{"type":"chat","id":"r1","content":"hi"}"#).unwrap();
+    let ClientMessage::Chat { conversation_id, on_disconnect, traceparent, .. } = chat else {
+        panic!("expected chat");
+    };
+    assert_eq!(conversation_id, None);
+    assert_eq!(on_disconnect, DisconnectPolicy::Fail);
+    assert_eq!(traceparent, None);
+
+    let ping: ClientMessage = serde_json::from_str(r#"{"type":"ping"}"#).unwrap();
+    assert!(matches!(ping, ClientMessage::Ping { timestamp: None }));
+    let pong = serde_json::to_string(&ServerMessage::Pong { timestamp: None }).unwrap();
+    assert_eq!(pong, r#"{"type":"pong"}"#);
+
+    let status: ServerMessage =
+        serde_json::from_str(r#"{"type":"status","gateway_connected":true}"#).unwrap();
+    assert!(matches!(
+        status,
+        ServerMessage::Status { gateway_rtt_ms: None, relay_reachable: None, agent_attached: None, .. }
+    ));
+
+    let error: ServerMessage =
+        serde_json::from_str(r#"{"type":"error","message":"boom"}"#).unwrap();
+    assert!(matches!(error, ServerMessage::Error { request_id: None, code: None, .. }));
+
+    let error: GatewayFrame =
+        serde_json::from_str(r#"{"type":"error","message":"boom"}"#).unwrap();
+    assert!(matches!(error, GatewayFrame::Error { request_id: None, code: None, .. }));
+    let json = serde_json::to_string(&GatewayFrame::request_error(
+        "r1",
+        ErrorCode::GatewayUnavailable,
+        "boom",
+    ))
+    .unwrap();
+    assert!(json.contains(r#""request_id":"r1""#) && json.contains(r#""code":"gateway_unavailable""#));
+}
+
+#[test]
+fn chat_request_omits_context_for_gateways_without_contexts() {
+    let json = serde_json::to_string(&GatewayFrame::chat_request("r1", "s1", None, "hi", None)).unwrap();
+    assert!(!json.contains("context_id"));
+
+    let json =
+        serde_json::to_string(&GatewayFrame::chat_request("r1", "s1", Some("c1"), "hi", None)).unwrap();
+    assert!(json.contains(r#""context_id":"c1""#));
+}
+
+#[test]
+fn malformed_traceparent_drops_the_trace_not_the_frame() {
+    for traceparent in [r#""garbage""#, r#""00-00000000000000000000000000000000-0000000000000001-01""#, "42"] {
+        let json = format!(r#"{{"type":"chat","id":"r1","content":"hi","traceparent":{traceparent}}}"#);
+        let chat: ClientMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(chat, ClientMessage::Chat { traceparent: None, .. }));
+    }
+
+    let json = serde_json::to_string(&GatewayFrame::chat_request("r1", "s1", None, "hi", None)).unwrap();
+    assert!(!json.contains("traceparent"));
+}
+
+#[test]
+fn handshakes_announce_this_version() {
+    let json = serde_json::to_string(&GatewayFrame::connect("n1", vec![Capability::Cancel])).unwrap();
+    let GatewayFrame::Connect { version, capabilities, .. } = serde_json::from_str(&json).unwrap()
+    else {
+        panic!("expected connect");
+    };
+    assert_eq!(version, PROTOCOL_VERSION);
+    assert_eq!(capabilities, vec![Capability::Cancel]);
+}
+
+/// `ServerMessage` as version 1 clients know it
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type")]
+#[allow(dead_code)]
+enum ServerMessageV1 {
+    #[serde(rename = "chat_reply")]
+    ChatReply { id: String, request_id: String, content: String, done: bool },
+    #[serde(rename = "error")]
+    Error { message: String },
+    #[serde(rename = "pong")]
+    Pong,
+    #[serde(rename = "status")]
+    Status { gateway_connected: bool },
+}
+
+#[test]
+fn version_1_clients_can_read_every_message_sent_to_them() {
+    let conversation = myclaw_common::protocol::ConversationSummary {
+        conversation_id: "c1".into(),
+        name: None,
+        message_count: 0,
+        updated_at: chrono::Utc::now(),
+    };
+    let messages = [
+        ServerMessage::Hello { version: PROTOCOL_VERSION, capabilities: vec![Capability::Cancel] },
+        ServerMessage::ChatReply {
+            id: "m1".into(),
+            request_id: "r1".into(),
+            conversation_id: "c1".into(),
+            content: "hi".into(),
+            done: true,
+            cancelled: true,
+        },
+        ServerMessage::request_error("r1", ErrorCode::IdleTimeout, "boom"),
+        ServerMessage::Retrying { request_id: "r1".into(), attempt: 1 },
+        ServerMessage::Pong { timestamp: Some(42) },
+        ServerMessage::Status {
+            gateway_connected: true,
+            gateway_rtt_ms: Some(12),
+            relay_reachable: Some(true),
+            agent_attached: Some(true),
+        },
+        ServerMessage::Authenticated { user_id: "alice".into() },
+        ServerMessage::Conversations { conversations: vec![conversation.clone()] },
+        ServerMessage::ConversationCreated { conversation },
+        ServerMessage::History { conversation_id: "c1".into(), entries: Vec::new(), has_more: false },
+    ];
+    for msg in messages {
+        let json = serde_json::to_string(&msg).unwrap();
+        let readable = serde_json::from_str::<ServerMessageV1>(&json).is_ok();
+        assert_eq!(readable, msg.min_version() == 1, "{json}");
+    }
+}
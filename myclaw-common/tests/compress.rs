@@ -0,0 +1,101 @@
+use myclaw_common::compress::{inflate, is_deflated, Compressor, MIN_COMPRESS_BYTES};
+use myclaw_common::protocol::{decode, Capability, Codec, Decoder, Encoder};
+use myclaw_common::{GatewayFrame, RelayFrame, ServerMessage};
+use tokio_tungstenite::tungstenite::Message;
+
+/// A long streamed chunk, as the gateway sends them.
+fn long_reply() -> GatewayFrame {
+    GatewayFrame::ChatResponse {
+        request_id: "r1".into(),
+        session_id: "myclaw-5f0c".into(),
+        content: "The relay forwards every chunk of the reply to the server. ".repeat(40),
+        done: false,
+    }
+}
+
+#[test]
+fn compressed_messages_round_trip() {
+    let mut compressor = Compressor::new(true);
+    let text = Codec::Json.encode(&long_reply()).unwrap();
+    let binary = Codec::Msgpack.encode(&long_reply()).unwrap();
+    for msg in [text, binary] {
+        let wire = compressor.compress(msg.clone());
+        assert!(is_deflated(&wire));
+        assert!(wire.len() < msg.len());
+        assert_eq!(inflate(wire).unwrap(), msg);
+    }
+}
+
+#[test]
+fn only_links_that_agreed_to_deflate_inflate() {
+    let mut encoder = Encoder::default();
+    encoder.negotiate(&[Capability::Deflate]);
+    let wire = encoder.encode(&long_reply()).unwrap();
+    assert!(is_deflated(&wire));
+    assert!(decode::<GatewayFrame>(&wire).is_err());
+    assert!(Decoder::default().decode::<GatewayFrame>(&wire).is_err());
+
+    let mut decoder = Decoder::default();
+    decoder.negotiate(&[Capability::Deflate]);
+    let frame: GatewayFrame = decoder.decode(&wire).unwrap();
+    assert!(matches!(frame, GatewayFrame::ChatResponse { done: false, .. }));
+}
+
+#[test]
+fn short_or_disabled_messages_are_sent_as_is() {
+    let mut disabled = Compressor::new(false);
+    let long = Codec::Json.encode(&long_reply()).unwrap();
+    assert_eq!(disabled.compress(long.clone()), long);
+
+    let mut enabled = Compressor::new(true);
+    let short = Message::Text("x".repeat(MIN_COMPRESS_BYTES - 1));
+    assert_eq!(enabled.compress(short.clone()), short);
+    let ping = Message::Ping(vec![0; 1024]);
+    assert_eq!(enabled.compress(ping.clone()), ping);
+}
+
+#[test]
+fn incompressible_messages_are_sent_as_is() {
+    // A byte sequence deflate cannot shrink
+    let mut state = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..4096)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let msg = Message::Binary(noise);
+    let mut compressor = Compressor::new(true);
+    assert_eq!(compressor.compress(msg.clone()), msg);
+    assert_eq!(compressor.stats().saved_bytes(), 0);
+}
+
+#[test]
+fn plain_binary_frames_are_not_mistaken_for_compressed_ones() {
+    let msg = Codec::Msgpack.encode(&ServerMessage::Pong { timestamp: None }).unwrap();
+    assert!(!is_deflated(&msg));
+    assert_eq!(inflate(msg.clone()).unwrap(), msg);
+
+    let forward = RelayFrame::forward("server-1", Message::Binary(vec![1, 2, 3]), None).unwrap();
+    assert!(!is_deflated(&Codec::Msgpack.encode(&forward).unwrap()));
+}
+
+#[test]
+fn corrupt_compressed_messages_are_rejected() {
+    assert!(inflate(Message::Binary(vec![0x01, 0xff, 0xff, 0xff])).is_err());
+}
+
+#[test]
+fn stats_measure_bandwidth_saved_on_a_streamed_reply() {
+    let mut compressor = Compressor::new(true);
+    for _ in 0..10 {
+        compressor.compress(Codec::Json.encode(&long_reply()).unwrap());
+    }
+    let stats = compressor.stats();
+    assert!(stats.raw_bytes > 0);
+    // Repetitive prose compresses well; the exact ratio depends on flate2
+    assert!(stats.saved_bytes() * 2 > stats.raw_bytes, "{stats}");
+    assert!(stats.to_string().contains("% saved"));
+}
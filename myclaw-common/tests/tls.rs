@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use myclaw_common::tls::Acceptor;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, connect_async_tls_with_config, Connector};
+
+/// Self-signed certificate for `localhost`, as (cert PEM, key PEM).
+fn self_signed() -> (String, String) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (certified.cert.pem(), certified.key_pair.serialize_pem())
+}
+
+/// Serve one WebSocket connection that echoes the first text message back.
+async fn echo_once(acceptor: Acceptor) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let Ok(stream) = acceptor.accept(stream).await else {
+            return;
+        };
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        if let Some(Ok(msg)) = ws.next().await {
+            ws.send(msg).await.unwrap();
+        }
+    });
+    port
+}
+
+fn trusting(cert_pem: &str) -> Connector {
+    let cert = native_tls::Certificate::from_pem(cert_pem.as_bytes()).unwrap();
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(cert)
+        .build()
+        .unwrap();
+    Connector::NativeTls(connector)
+}
+
+#[tokio::test]
+async fn wss_round_trip_with_self_signed_cert() {
+    let (cert, key) = self_signed();
+    let acceptor = Acceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+    assert_eq!(acceptor.scheme(), "wss");
+    let port = echo_once(acceptor).await;
+
+    let url = format!("wss://localhost:{port}");
+    let (mut ws, _) = connect_async_tls_with_config(url, None, false, Some(trusting(&cert)))
+        .await
+        .unwrap();
+    ws.send(Message::Text("hello".into())).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::Text("hello".into()));
+}
+
+#[tokio::test]
+async fn wss_rejects_untrusted_cert() {
+    let (cert, key) = self_signed();
+    let acceptor = Acceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+    let port = echo_once(acceptor).await;
+
+    // Default connector only trusts the system roots
+    assert!(connect_async(format!("wss://localhost:{port}")).await.is_err());
+}
+
+#[tokio::test]
+async fn plain_acceptor_serves_ws() {
+    let acceptor = Acceptor::plain();
+    assert_eq!(acceptor.scheme(), "ws");
+    let port = echo_once(acceptor).await;
+
+    let (mut ws, _) = connect_async(format!("ws://127.0.0.1:{port}")).await.unwrap();
+    ws.send(Message::Text("hello".into())).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::Text("hello".into()));
+}
+
+#[tokio::test]
+async fn stalled_tls_handshake_times_out() {
+    let (cert, key) = self_signed();
+    let acceptor = Acceptor::from_pem(cert.as_bytes(), key.as_bytes())
+        .unwrap()
+        .with_handshake_timeout(Duration::from_millis(100));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Connects, then never sends a ClientHello
+    let _idle = TcpStream::connect(addr).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let accepted = tokio::time::timeout(Duration::from_secs(5), acceptor.accept(stream)).await;
+    assert!(matches!(accepted, Ok(Err(_))));
+}
+
+#[test]
+fn from_config_loads_pem_files() {
+    let (cert, key) = self_signed();
+    let dir = std::env::temp_dir().join(format!("myclaw-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert_path: PathBuf = dir.join("cert.pem");
+    let key_path: PathBuf = dir.join("key.pem");
+    std::fs::write(&cert_path, cert).unwrap();
+    std::fs::write(&key_path, key).unwrap();
+
+    let acceptor = Acceptor::from_config(Some(&cert_path), Some(&key_path)).unwrap();
+    assert!(acceptor.is_tls());
+    assert!(!Acceptor::from_config(None, None).unwrap().is_tls());
+    assert!(Acceptor::from_config(Some(&cert_path), None).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
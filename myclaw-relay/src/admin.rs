@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use myclaw_common::auth::key_eq;
+use crate::bridge::{AgentSummary, BridgeHandle, ServerSummary};
+
+#[derive(Clone)]
+struct Admin {
+    bridge: Arc<RwLock<BridgeHandle>>,
+    token: Option<Arc<str>>,
+}
+
+/// Body of `GET /peers`
+#[derive(Serialize)]
+struct Peers {
+    agents: Vec<AgentSummary>,
+    servers: Vec<ServerSummary>,
+}
+
+/// Serve the admin API:
+/// `GET /peers` lists connected agents and servers,
+/// `DELETE /agents/<agent_id>` and `DELETE /servers/<server_id>` disconnect one.
+pub async fn run(
+    listener: TcpListener,
+    bridge: Arc<RwLock<BridgeHandle>>,
+    token: Option<String>,
+) -> anyhow::Result<()> {
+    info!("admin: listening on {}", listener.local_addr()?);
+    let admin = Admin {
+        bridge,
+        token: token.map(Into::into),
+    };
+    let app = Router::new()
+        .route("/peers", get(peers))
+        .route("/agents/:agent_id", delete(kick_agent))
+        .route("/servers/:server_id", delete(kick_server))
+        .layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Requests must carry `Authorization: Bearer <token>` when a token is configured,
+/// which it is unless the listener is on a loopback address.
+async fn authorize(State(admin): State<Admin>, req: Request, next: Next) -> Response {
+    if let Some(token) = &admin.token {
+        let presented = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !presented.is_some_and(|presented| key_eq(presented, token)) {
+            warn!("admin: rejected {} {}: bad token", req.method(), req.uri().path());
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+async fn peers(State(admin): State<Admin>) -> Json<Peers> {
+    let bridge = admin.bridge.read().await;
+    Json(Peers {
+        agents: bridge.agent_summaries(),
+        servers: bridge.server_summaries(),
+    })
+}
+
+async fn kick_agent(State(admin): State<Admin>, Path(agent_id): Path<String>) -> StatusCode {
+    if admin.bridge.write().await.kick_agent(&agent_id) {
+        info!("admin: kicked agent '{}'", agent_id);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn kick_server(State(admin): State<Admin>, Path(server_id): Path<String>) -> StatusCode {
+    if admin.bridge.write().await.unregister_server(&server_id) {
+        info!("admin: kicked {}", server_id);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
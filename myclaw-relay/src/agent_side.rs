@@ -1,99 +1,198 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use futures_util::{SinkExt, StreamExt};
 use tokio_tungstenite::accept_async;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{info, warn};
+use tracing::{debug, field, info, info_span, warn};
 
-use myclaw_common::RelayFrame;
-use crate::bridge::{BridgeHandle, Tx};
+use myclaw_common::compress::SentCounters;
+use myclaw_common::protocol::{
+    common_capabilities, negotiate_version, Capability, Decoder, Encoder, MIN_RELAY_VERSION,
+};
+use myclaw_common::{RejectCode, RelayFrame};
+use myclaw_common::tls::Acceptor;
+use myclaw_common::trace::{self, FrameHead};
+use crate::bridge::{AgentTx, BridgeHandle, PeerInfo};
+use crate::config::ListenConfig;
+use crate::handshake::{read_hello, reject};
+use crate::keepalive::{next_message, pinger};
+use crate::queue;
 
-/// Accept a single agent-side (myclaw-agent) WebSocket connection.
-/// Perform RelayFrame handshake, then bridge messages bidirectionally.
-pub async fn run(listener: TcpListener, bridge: Arc<RwLock<BridgeHandle>>) -> anyhow::Result<()> {
-    info!("agent_side: waiting for myclaw-agent connection");
+/// Features the relay offers agents in `agent_welcome`
+const AGENT_CAPABILITIES: [Capability; 2] = [Capability::Msgpack, Capability::Deflate];
+
+/// Accept myclaw-agent WebSocket connections; each agent is handled concurrently.
+pub async fn run(
+    listener: TcpListener,
+    acceptor: Acceptor,
+    bridge: Arc<RwLock<BridgeHandle>>,
+    cfg: Arc<ListenConfig>,
+) -> anyhow::Result<()> {
+    info!("agent_side: waiting for myclaw-agent connections");
 
     loop {
         let (stream, addr) = listener.accept().await?;
         info!("agent_side: incoming connection from {}", addr);
 
-        let ws = accept_async(stream).await?;
-        let (mut ws_tx, mut ws_rx) = ws.split();
-
-        // --- Handshake: expect AgentHello ---
-        let agent_id = match ws_rx.next().await {
-            Some(Ok(Message::Text(text))) => {
-                match serde_json::from_str::<RelayFrame>(&text) {
-                    Ok(RelayFrame::AgentHello { agent_id }) => {
-                        info!("agent_side: agent hello from '{}'", agent_id);
-                        agent_id
-                    }
-                    _ => {
-                        warn!("agent_side: expected AgentHello, got: {}", text);
-                        continue;
-                    }
-                }
-            }
-            _ => {
-                warn!("agent_side: connection closed before handshake");
-                continue;
+        let acceptor = acceptor.clone();
+        let bridge = bridge.clone();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_agent(stream, addr, acceptor, bridge, cfg).await {
+                warn!("agent_side: connection from {} failed: {}", addr, e);
             }
-        };
-
-        // Send AgentWelcome
-        let welcome = serde_json::to_string(&RelayFrame::AgentWelcome {
-            agent_id: agent_id.clone(),
-        })?;
-        if ws_tx.send(Message::Text(welcome)).await.is_err() {
-            warn!("agent_side: failed to send welcome");
-            continue;
-        }
-        info!("agent_side: agent '{}' registered", agent_id);
+        });
+    }
+}
 
-        // Create channel: server_side will send to this tx
-        let (tx, mut rx): (Tx, _) = tokio::sync::mpsc::unbounded_channel();
+/// Authenticate and register the agent, then bridge messages bidirectionally.
+async fn handle_agent(
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: Acceptor,
+    bridge: Arc<RwLock<BridgeHandle>>,
+    cfg: Arc<ListenConfig>,
+) -> anyhow::Result<()> {
+    let stream = acceptor.accept(stream).await?;
+    let ws = accept_async(stream).await?;
+    let (mut ws_tx, mut ws_rx) = ws.split();
 
-        {
-            let mut b = bridge.write().await;
-            b.agent_tx = Some(tx);
+    // --- Handshake: expect AgentHello with a key from relay.toml ---
+    let (agent_id, version, capabilities) = match read_hello(&mut ws_rx).await {
+        Ok(RelayFrame::AgentHello { agent_id, key, version, capabilities }) => {
+            let Some(version) = negotiate_version(version, MIN_RELAY_VERSION) else {
+                warn!("agent_side: rejected agent '{}' from {}: protocol v{}", agent_id, addr, version);
+                let message = format!("protocol version {} is older than {}", version, MIN_RELAY_VERSION);
+                reject(&mut ws_tx, RejectCode::UnsupportedVersion, message).await;
+                return Ok(());
+            };
+            if !cfg.agent_authorized(&agent_id, &key) {
+                warn!("agent_side: rejected agent '{}' from {}: bad credentials", agent_id, addr);
+                reject(&mut ws_tx, RejectCode::Unauthorized, "unknown agent or wrong key").await;
+                return Ok(());
+            }
+            let capabilities = common_capabilities(&AGENT_CAPABILITIES, &capabilities);
+            info!("agent_side: agent hello from '{}' (protocol v{}, {:?})", agent_id, version, capabilities);
+            (agent_id, version, capabilities)
+        }
+        Ok(_) => {
+            warn!("agent_side: rejected {}: expected AgentHello", addr);
+            reject(&mut ws_tx, RejectCode::BadHandshake, "expected agent_hello").await;
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("agent_side: rejected {}: {}", addr, e);
+            reject(&mut ws_tx, RejectCode::BadHandshake, "expected agent_hello").await;
+            return Ok(());
         }
+    };
 
-        let bridge_read = bridge.clone();
-        let bridge_cleanup = bridge.clone();
+    // Send AgentWelcome; frames after it use the negotiated codec and compression
+    let mut encoder = Encoder::counted(SentCounters::new("myclaw_relay", "agent"));
+    encoder.negotiate(&capabilities);
+    let mut decoder = Decoder::default();
+    decoder.negotiate(&capabilities);
+    let welcome = serde_json::to_string(&RelayFrame::AgentWelcome {
+        agent_id: agent_id.clone(),
+        version,
+        capabilities,
+    })?;
+    ws_tx.send(Message::Text(welcome)).await?;
 
-        // Task: forward from rx → ws (server → agent)
-        let send_task = tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if ws_tx.send(Message::Text(msg)).await.is_err() {
-                    break;
-                }
-            }
-        });
+    // Create queue: server_side sends RelayFrame::Forward / Close to this tx
+    let depth = metrics::gauge!("myclaw_relay_queue_depth", "side" => "agent", "peer" => agent_id.clone());
+    let (tx, mut rx): (AgentTx, _) = queue::bounded(cfg.queue.capacity, cfg.queue.overflow, depth);
+    let info = PeerInfo::new(addr);
+    let stats = info.stats.clone();
+    let conn_id = bridge.write().await.register_agent(&agent_id, tx, info);
+    info!("agent_side: agent '{}' registered from {}", agent_id, addr);
 
-        // Read from ws → forward to server_tx (agent → server)
-        while let Some(Ok(msg)) = ws_rx.next().await {
-            match msg {
-                Message::Text(text) => {
-                    let b = bridge_read.read().await;
-                    if let Some(ref server_tx) = b.server_tx {
-                        if server_tx.send(text).is_err() {
-                            warn!("agent_side: server_tx send failed");
+    // Task: forward from rx → ws (server → agent), pinging the agent in between.
+    // Ends when the agent is replaced or unregistered and its sender dropped.
+    let task_stats = stats.clone();
+    let mut pings = pinger(cfg.ping_interval());
+    let send_task = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                frame = rx.recv() => {
+                    let Some(frame) = frame else { break };
+                    match encoder.encode(&frame) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!("agent_side: failed to encode frame: {}", e);
+                            continue;
                         }
-                    } else {
-                        warn!("agent_side: no server connected, dropping message");
                     }
                 }
-                Message::Close(_) => break,
-                _ => {}
+                _ = pings.tick() => Message::Ping(Vec::new()),
+            };
+            task_stats.record_out(&msg);
+            if ws_tx.send(msg).await.is_err() {
+                break;
             }
         }
+        let _ = ws_tx.close().await;
+    });
 
-        send_task.abort();
-        {
-            let mut b = bridge_cleanup.write().await;
-            b.agent_tx = None;
+    // Read from ws → forward to the addressed server (agent → server);
+    // an agent silent past the idle timeout is treated as gone
+    let peer = format!("agent '{}'", agent_id);
+    let forwarded = metrics::counter!("myclaw_relay_forwarded_bytes_total", "direction" => "agent_to_server");
+    while let Some(msg) = next_message(&mut ws_rx, cfg.idle_timeout(), &peer).await {
+        stats.record_in(&msg);
+        let (server_id, payload, traceparent) = match msg {
+            Message::Text(_) | Message::Binary(_) => match decoder.decode::<RelayFrame>(&msg) {
+                Ok(RelayFrame::Forward { server_id, payload, traceparent }) => {
+                    (server_id, Message::Text(payload), traceparent)
+                }
+                Ok(RelayFrame::ForwardBinary { server_id, payload, traceparent }) => {
+                    (server_id, Message::Binary(payload), traceparent)
+                }
+                Ok(RelayFrame::Close { server_id }) => {
+                    info!("agent_side: '{}' closed gateway link of {}", agent_id, server_id);
+                    bridge.write().await.detach_server(&agent_id, &server_id);
+                    continue;
+                }
+                Ok(frame) => {
+                    warn!("agent_side: unexpected frame from '{}': {:?}", agent_id, frame);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("agent_side: bad frame from '{}': {}", agent_id, e);
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+        if let Some(head) = FrameHead::peek(&payload) {
+            if let Some(request_id) = head.traced() {
+                let span = info_span!("forward", %server_id, kind = %head.kind, request_id, trace_id = field::Empty);
+                trace::hop(&span, traceparent);
+                span.in_scope(|| debug!("agent_side: {} → server", head.kind));
+            }
         }
+        let server_tx = bridge.read().await.server_tx(&agent_id, &server_id);
+        if let Some(server_tx) = server_tx {
+            let len = payload.len() as u64;
+            // Never waits: this loop reads for every server of the agent, so a server
+            // too slow to keep up is disconnected rather than holding up the others
+            match server_tx.try_send(payload) {
+                Ok(()) => forwarded.increment(len),
+                Err(e) => warn!("agent_side: {} send failed: {}", server_id, e),
+            }
+        } else {
+            warn!("agent_side: '{}' addressed unknown {}, dropping message",
+                agent_id, server_id);
+        }
+    }
+
+    send_task.abort();
+    if bridge.write().await.unregister_agent(&agent_id, conn_id) {
         warn!("agent_side: agent '{}' disconnected", agent_id);
+    } else {
+        info!("agent_side: replaced or kicked connection for agent '{}' closed", agent_id);
     }
+    Ok(())
 }
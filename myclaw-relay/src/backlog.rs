@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+use myclaw_common::protocol::{decode, ErrorCode};
+use myclaw_common::{GatewayFrame, RelayFrame};
+use crate::config::BacklogConfig;
+
+/// Frames a server sent while its agent was away, oldest first.
+pub struct Backlog {
+    /// When the agent went away
+    since: Instant,
+    frames: VecDeque<RelayFrame>,
+    /// Payload bytes of `frames`
+    bytes: usize,
+}
+
+impl Backlog {
+    pub fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            frames: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Hold a frame, or hand it back if it would take the backlog past `limits`.
+    pub fn push(&mut self, frame: RelayFrame, limits: &BacklogConfig) -> Result<(), RelayFrame> {
+        let size = payload_len(&frame);
+        if self.frames.len() >= limits.max_messages || self.bytes + size > limits.max_bytes {
+            return Err(frame);
+        }
+        self.bytes += size;
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    /// The agent has been away for at least `ttl`
+    pub fn expired(&self, ttl: Duration) -> bool {
+        self.since.elapsed() >= ttl
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn into_frames(self) -> VecDeque<RelayFrame> {
+        self.frames
+    }
+}
+
+fn payload_len(frame: &RelayFrame) -> usize {
+    match frame {
+        RelayFrame::Forward { payload, .. } => payload.len(),
+        RelayFrame::ForwardBinary { payload, .. } => payload.len(),
+        _ => 0,
+    }
+}
+
+/// `error` failing the chat request a forwarded frame carries, for its server;
+/// `None` for any other frame, which is dropped without a word.
+pub fn request_error(frame: &RelayFrame, message: &str) -> Option<Message> {
+    let msg = match frame {
+        RelayFrame::Forward { payload, .. } => Message::Text(payload.clone()),
+        RelayFrame::ForwardBinary { payload, .. } => Message::Binary(payload.clone()),
+        _ => return None,
+    };
+    let GatewayFrame::ChatRequest { request_id, .. } = decode(&msg).ok()? else {
+        return None;
+    };
+    let error = GatewayFrame::request_error(&request_id, ErrorCode::GatewayUnavailable, message);
+    serde_json::to_string(&error).ok().map(Message::Text)
+}
+
+/// `error` telling a server its agent went away, so replies in flight will not come,
+/// while what it sends next is held for `ttl`.
+pub fn held_notice(agent_id: &str, ttl: Duration) -> Message {
+    let notice = GatewayFrame::Error {
+        request_id: None,
+        code: Some(ErrorCode::GatewayLost),
+        message: format!("agent '{}' went away, holding messages for {:?}", agent_id, ttl),
+    };
+    Message::Text(serde_json::to_string(&notice).unwrap_or_default())
+}
@@ -1,17 +1,695 @@
-use tokio::sync::mpsc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+
+use myclaw_common::RelayFrame;
+use crate::backlog::{held_notice, request_error, Backlog};
+use crate::config::BacklogConfig;
+use crate::queue::QueueTx;
+
+/// Frames for an agent, encoded with the codec of its link.
+pub type AgentTx = QueueTx<RelayFrame>;
+/// Gateway messages for a server, Text or Binary as the gateway sent them.
+pub type ServerTx = QueueTx<Message>;
+
+/// Where a peer connected from and when, with its traffic so far.
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub stats: Arc<PeerStats>,
+}
+
+impl PeerInfo {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            connected_at: Utc::now(),
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Data messages through one connection as sent on the wire, counted by its
+/// read loop and send task.
+#[derive(Default)]
+pub struct PeerStats {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl PeerStats {
+    pub fn record_in(&self, msg: &Message) {
+        if msg.is_text() || msg.is_binary() {
+            self.messages_in.fetch_add(1, Ordering::Relaxed);
+            self.bytes_in.fetch_add(msg.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_out(&self, msg: &Message) {
+        if msg.is_text() || msg.is_binary() {
+            self.messages_out.fetch_add(1, Ordering::Relaxed);
+            self.bytes_out.fetch_add(msg.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn counters(&self) -> Counters {
+        Counters {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Counters {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// A connected agent, as listed by the admin endpoint.
+#[derive(Serialize)]
+pub struct AgentSummary {
+    pub agent_id: String,
+    pub remote_addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    /// Frames queued for the agent
+    pub queue_depth: usize,
+    /// Servers attached to the agent
+    pub servers: Vec<String>,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// A connected server, as listed by the admin endpoint.
+#[derive(Serialize)]
+pub struct ServerSummary {
+    pub server_id: String,
+    /// Name of the credential it authenticated with
+    pub name: String,
+    pub remote_addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    /// Its agent; while held, the one that went away, if any
+    pub agent_id: String,
+    /// Waiting for an agent to come back
+    pub held: bool,
+    pub held_messages: usize,
+    /// Messages queued for the server
+    pub queue_depth: usize,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// A registered myclaw-agent connection.
+pub struct AgentPeer {
+    /// Distinguishes successive connections that reuse the same agent_id.
+    pub conn_id: u64,
+    pub tx: AgentTx,
+    pub info: PeerInfo,
+}
+
+/// A myclaw-server connection attached to one agent.
+pub struct ServerPeer {
+    pub tx: ServerTx,
+    /// Name of the credential it authenticated with
+    pub name: String,
+    pub info: PeerInfo,
+    /// Agent that receives this server's traffic; while held, the one that went away,
+    /// empty if it has not had one yet
+    pub agent_id: String,
+    /// Agents the server may be attached to
+    candidates: Vec<String>,
+    /// Changed by the server's read loop under the bridge's read lock
+    link: Mutex<ServerLink>,
+}
+
+#[derive(Default)]
+struct ServerLink {
+    /// The server's first frame, its gateway `connect`, replayed to the next agent
+    handshake: Option<RelayFrame>,
+    /// Set while the server waits for an agent
+    held: Option<Backlog>,
+}
+
+impl ServerPeer {
+    fn link(&self) -> MutexGuard<'_, ServerLink> {
+        self.link.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn link_mut(&mut self) -> &mut ServerLink {
+        self.link.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn held(&self) -> bool {
+        self.link().held.is_some()
+    }
+}
+
+/// How a server was attached.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Attached {
+    /// To this agent
+    Agent(String),
+    /// To none yet: what it sends is held until one of its agents connects
+    Held,
+}
+
+/// Where a frame from a server goes.
+pub enum Route {
+    /// To the queue of the server's agent
+    Agent(AgentTx, RelayFrame),
+    /// Held until an agent comes back
+    Held,
+    /// Past the backlog limits: dropped, failing the chat request it carried
+    Refused,
+    /// Nowhere: the server is no longer attached
+    Detached,
+}
 
-pub type Tx = mpsc::UnboundedSender<String>;
 /// Shared state that bridges server-side and agent-side connections.
 pub struct BridgeHandle {
-    pub server_tx: Option<Tx>,
-    pub agent_tx: Option<Tx>,
+    /// agent_id → connected agent
+    pub agents: HashMap<String, AgentPeer>,
+    /// server_id → connected server
+    pub servers: HashMap<String, ServerPeer>,
+    backlog: BacklogConfig,
+    next_conn_id: u64,
 }
 
 impl BridgeHandle {
-    pub fn new() -> Self {
+    pub fn new(backlog: BacklogConfig) -> Self {
         Self {
-            server_tx: None,
-            agent_tx: None,
+            agents: HashMap::new(),
+            servers: HashMap::new(),
+            backlog,
+            next_conn_id: 0,
+        }
+    }
+
+    /// Register an agent connection, replacing any stale connection with the same id.
+    /// Returns the connection id needed to unregister it later.
+    pub fn register_agent(&mut self, agent_id: &str, tx: AgentTx, info: PeerInfo) -> u64 {
+        self.next_conn_id += 1;
+        let conn_id = self.next_conn_id;
+        if self
+            .agents
+            .insert(agent_id.to_string(), AgentPeer { conn_id, tx, info })
+            .is_some()
+        {
+            warn!("bridge: agent '{}' re-registered, dropping previous connection", agent_id);
+            // Gateway links of the old connection are gone with it
+            self.hold_servers_of(agent_id);
+        }
+        self.resume_held();
+        conn_id
+    }
+
+    /// Remove an agent, unless it has already been replaced by a newer connection.
+    /// Servers attached to it are held for another agent, or dropped so they reconnect elsewhere.
+    pub fn unregister_agent(&mut self, agent_id: &str, conn_id: u64) -> bool {
+        match self.agents.get(agent_id) {
+            Some(peer) if peer.conn_id == conn_id => {
+                self.agents.remove(agent_id);
+                self.hold_servers_of(agent_id);
+                self.resume_held();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A fresh id for a server connection, unique for the life of the relay.
+    pub fn new_server_id(&mut self) -> String {
+        self.next_conn_id += 1;
+        format!("server-{}", self.next_conn_id)
+    }
+
+    /// Attach a server to the first connected agent among `candidates`.
+    /// With none connected, the server is held for the backlog TTL as if its agent
+    /// had gone away; `None` if there is no TTL to hold it for.
+    pub fn attach_server(
+        &mut self,
+        server_id: &str,
+        tx: ServerTx,
+        name: &str,
+        info: PeerInfo,
+        candidates: &[String],
+    ) -> Option<Attached> {
+        let agent_id = pick_agent(&self.agents, candidates);
+        if agent_id.is_none() && self.backlog.ttl().is_zero() {
+            return None;
         }
+        let held = agent_id.is_none().then(Backlog::new);
+        self.servers.insert(
+            server_id.to_string(),
+            ServerPeer {
+                tx,
+                name: name.to_string(),
+                info,
+                agent_id: agent_id.clone().unwrap_or_default(),
+                candidates: candidates.to_vec(),
+                link: Mutex::new(ServerLink { handshake: None, held }),
+            },
+        );
+        Some(agent_id.map_or(Attached::Held, Attached::Agent))
+    }
+
+    /// Remove a server and tell its agent to close the matching gateway link.
+    /// Returns whether the server was still connected.
+    pub fn unregister_server(&mut self, server_id: &str) -> bool {
+        let Some(peer) = self.servers.remove(server_id) else {
+            return false;
+        };
+        if peer.held() {
+            return true;
+        }
+        if let Some(agent) = self.agents.get(&peer.agent_id) {
+            let _ = agent.tx.send_now(RelayFrame::Close {
+                server_id: server_id.to_string(),
+            });
+        }
+        true
+    }
+
+    /// Disconnect an agent as if it had gone away. Returns whether it was connected.
+    pub fn kick_agent(&mut self, agent_id: &str) -> bool {
+        let Some(conn_id) = self.agents.get(agent_id).map(|peer| peer.conn_id) else {
+            return false;
+        };
+        self.unregister_agent(agent_id, conn_id)
+    }
+
+    /// Connected agents, by agent_id
+    pub fn agent_summaries(&self) -> Vec<AgentSummary> {
+        let mut agents: Vec<AgentSummary> = self
+            .agents
+            .iter()
+            .map(|(agent_id, peer)| {
+                let mut servers: Vec<String> = self
+                    .servers
+                    .iter()
+                    .filter(|(_, server)| !server.held() && server.agent_id == *agent_id)
+                    .map(|(server_id, _)| server_id.clone())
+                    .collect();
+                servers.sort();
+                AgentSummary {
+                    agent_id: agent_id.clone(),
+                    remote_addr: peer.info.addr,
+                    connected_at: peer.info.connected_at,
+                    queue_depth: peer.tx.len(),
+                    servers,
+                    counters: peer.info.stats.counters(),
+                }
+            })
+            .collect();
+        agents.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        agents
+    }
+
+    /// Connected servers, oldest first
+    pub fn server_summaries(&self) -> Vec<ServerSummary> {
+        let mut servers: Vec<ServerSummary> = self
+            .servers
+            .iter()
+            .map(|(server_id, peer)| {
+                let held = peer.link().held.as_ref().map(Backlog::len);
+                ServerSummary {
+                    server_id: server_id.clone(),
+                    name: peer.name.clone(),
+                    remote_addr: peer.info.addr,
+                    connected_at: peer.info.connected_at,
+                    agent_id: peer.agent_id.clone(),
+                    held: held.is_some(),
+                    held_messages: held.unwrap_or(0),
+                    queue_depth: peer.tx.len(),
+                    counters: peer.info.stats.counters(),
+                }
+            })
+            .collect();
+        servers.sort_by_key(|server| server.connected_at);
+        servers
+    }
+
+    /// Drop a server whose gateway link was closed by its agent.
+    pub fn detach_server(&mut self, agent_id: &str, server_id: &str) {
+        if self.server_tx(agent_id, server_id).is_some() {
+            self.servers.remove(server_id);
+        }
+    }
+
+    /// Route a frame from a server to its agent, or into its backlog while it has none.
+    pub fn route(&self, server_id: &str, frame: RelayFrame) -> Route {
+        let Some(peer) = self.servers.get(server_id) else {
+            return Route::Detached;
+        };
+        let mut link = peer.link();
+        if link.handshake.is_none() {
+            link.handshake = Some(frame.clone());
+            if link.held.is_some() {
+                // Replayed first when an agent comes back
+                return Route::Held;
+            }
+        }
+        let Some(backlog) = link.held.as_mut() else {
+            return match self.agents.get(&peer.agent_id) {
+                Some(agent) => Route::Agent(agent.tx.clone(), frame),
+                None => Route::Detached,
+            };
+        };
+        match backlog.push(frame, &self.backlog) {
+            Ok(()) => Route::Held,
+            Err(frame) => {
+                warn!("bridge: backlog of {} full, refusing message", server_id);
+                if let Some(error) = request_error(&frame, "relay backlog full while the agent is away") {
+                    let _ = peer.tx.send_now(error);
+                }
+                Route::Refused
+            }
+        }
+    }
+
+    /// Give up on servers held past the backlog TTL: fail the chat requests they sent
+    /// meanwhile, then drop them so they reconnect.
+    pub fn expire_held(&mut self) {
+        let ttl = self.backlog.ttl();
+        let expired: Vec<String> = self
+            .servers
+            .iter()
+            .filter(|(_, peer)| peer.link().held.as_ref().is_some_and(|backlog| backlog.expired(ttl)))
+            .map(|(server_id, _)| server_id.clone())
+            .collect();
+        for server_id in expired {
+            let Some(peer) = self.servers.remove(&server_id) else {
+                continue;
+            };
+            let ServerPeer { tx, link, .. } = peer;
+            let Some(backlog) = link.into_inner().unwrap_or_else(|e| e.into_inner()).held else {
+                continue;
+            };
+            warn!("bridge: no agent for {} within {:?}, failing {} held messages",
+                server_id, ttl, backlog.len());
+            let message = format!("no agent came back within {:?}", ttl);
+            for frame in backlog.into_frames() {
+                if let Some(error) = request_error(&frame, &message) {
+                    let _ = tx.send_now(error);
+                }
+            }
+        }
+    }
+
+    /// Sender of a server, only if it is attached to `agent_id`.
+    pub fn server_tx(&self, agent_id: &str, server_id: &str) -> Option<ServerTx> {
+        self.servers
+            .get(server_id)
+            .filter(|peer| peer.agent_id == agent_id)
+            .map(|peer| peer.tx.clone())
+    }
+
+    /// Start holding the servers of an agent that went away, telling each of them.
+    /// Without a backlog TTL they are detached instead: dropping a server's sender
+    /// ends its send task, which closes the socket.
+    fn hold_servers_of(&mut self, agent_id: &str) {
+        let ttl = self.backlog.ttl();
+        self.servers.retain(|server_id, peer| {
+            if peer.agent_id != agent_id || peer.held() {
+                return true;
+            }
+            if ttl.is_zero() {
+                warn!("bridge: detaching {} from agent '{}'", server_id, agent_id);
+                return false;
+            }
+            warn!("bridge: holding {} for up to {:?} after losing agent '{}'", server_id, ttl, agent_id);
+            peer.link_mut().held = Some(Backlog::new());
+            let _ = peer.tx.send_now(held_notice(agent_id, ttl));
+            true
+        });
+    }
+
+    /// Attach held servers to any of their agents that is connected, replaying the
+    /// gateway handshake and then the backlog; the agent opens a fresh gateway link for each.
+    fn resume_held(&mut self) {
+        for (server_id, peer) in self.servers.iter_mut() {
+            if !peer.held() {
+                continue;
+            }
+            let Some(agent_id) = pick_agent(&self.agents, &peer.candidates) else {
+                continue;
+            };
+            let link = peer.link_mut();
+            let Some(backlog) = link.held.take() else {
+                continue;
+            };
+            info!("bridge: resuming {} on agent '{}' with {} held messages",
+                server_id, agent_id, backlog.len());
+            let agent = &self.agents[&agent_id];
+            // Already held to the backlog limits, so they go in past the queue capacity
+            for frame in link.handshake.iter().cloned().chain(backlog.into_frames()) {
+                let _ = agent.tx.send_now(frame);
+            }
+            peer.agent_id = agent_id;
+        }
+    }
+}
+
+/// The first connected agent among `candidates`
+fn pick_agent(agents: &HashMap<String, AgentPeer>, candidates: &[String]) -> Option<String> {
+    candidates
+        .iter()
+        .find(|id| agents.contains_key(*id))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use metrics::Gauge;
+    use tokio::time::Duration;
+
+    use myclaw_common::protocol::{decode, ErrorCode};
+    use myclaw_common::GatewayFrame;
+    use crate::queue::{self, OverflowPolicy, QueueRx};
+
+    use super::*;
+
+    fn bridge(ttl_secs: u64, max_messages: usize, max_bytes: usize) -> BridgeHandle {
+        BridgeHandle::new(BacklogConfig { ttl_secs, max_messages, max_bytes })
+    }
+
+    fn info() -> PeerInfo {
+        PeerInfo::new(([127, 0, 0, 1], 0).into())
+    }
+
+    fn agent(bridge: &mut BridgeHandle, agent_id: &str) -> QueueRx<RelayFrame> {
+        let (tx, rx) = queue::bounded(16, OverflowPolicy::Block, Gauge::noop());
+        bridge.register_agent(agent_id, tx, info());
+        rx
+    }
+
+    fn server(bridge: &mut BridgeHandle, agent_id: &str) -> (String, Option<Attached>, QueueRx<Message>) {
+        let (tx, rx) = queue::bounded(16, OverflowPolicy::Block, Gauge::noop());
+        let server_id = bridge.new_server_id();
+        let attached = bridge.attach_server(&server_id, tx, "office", info(), &[agent_id.to_string()]);
+        (server_id, attached, rx)
+    }
+
+    fn connect(server_id: &str) -> RelayFrame {
+        let connect = serde_json::to_string(&GatewayFrame::connect("node-1", Vec::new())).unwrap();
+        RelayFrame::forward(server_id, Message::Text(connect), None).unwrap()
+    }
+
+    fn chat(server_id: &str, request_id: &str) -> RelayFrame {
+        let request = GatewayFrame::chat_request(request_id, "gw-1", None, "hello", None);
+        let request = serde_json::to_string(&request).unwrap();
+        RelayFrame::forward(server_id, Message::Text(request), None).unwrap()
+    }
+
+    /// What a frame carries: `connect`, or the request_id of a chat request
+    fn carried(frame: RelayFrame) -> String {
+        let RelayFrame::Forward { payload, .. } = frame else {
+            panic!("expected a forward frame, got {:?}", frame);
+        };
+        match serde_json::from_str(&payload).unwrap() {
+            GatewayFrame::Connect { .. } => "connect".into(),
+            GatewayFrame::ChatRequest { request_id, .. } => request_id,
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    fn error(msg: Message) -> (Option<String>, Option<ErrorCode>) {
+        match decode(&msg).unwrap() {
+            GatewayFrame::Error { request_id, code, .. } => (request_id, code),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    fn queued<T>(rx: &mut QueueRx<T>) -> Vec<T> {
+        let mut items = Vec::new();
+        while let Some(Some(item)) = rx.recv().now_or_never() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn servers_without_an_agent_are_held_and_replayed_in_order() {
+        let mut bridge = bridge(60, 10, 1 << 20);
+        let (server_id, attached, mut server_rx) = server(&mut bridge, "agent-a");
+        assert_eq!(attached, Some(Attached::Held));
+        assert!(bridge.server_summaries()[0].held);
+
+        assert!(matches!(bridge.route(&server_id, connect(&server_id)), Route::Held));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-1")), Route::Held));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-2")), Route::Held));
+        assert_eq!(bridge.server_summaries()[0].held_messages, 2);
+
+        let mut agent_rx = agent(&mut bridge, "agent-a");
+        let replayed: Vec<String> = queued(&mut agent_rx).into_iter().map(carried).collect();
+        assert_eq!(replayed, ["connect", "req-1", "req-2"]);
+        assert_eq!(bridge.server_summaries()[0].agent_id, "agent-a");
+        assert_eq!(bridge.agent_summaries()[0].servers, [server_id.as_str()]);
+
+        let Route::Agent(_, frame) = bridge.route(&server_id, chat(&server_id, "req-3")) else {
+            panic!("expected the frame to go to the agent");
+        };
+        assert_eq!(carried(frame), "req-3");
+        assert!(queued(&mut server_rx).is_empty());
+    }
+
+    #[test]
+    fn servers_are_refused_without_a_backlog_ttl() {
+        let mut bridge = bridge(0, 10, 1 << 20);
+        let (_, attached, _server_rx) = server(&mut bridge, "agent-a");
+        assert_eq!(attached, None);
+        assert!(bridge.servers.is_empty());
+    }
+
+    #[test]
+    fn losing_the_agent_holds_its_servers() {
+        let mut bridge = bridge(60, 10, 1 << 20);
+        let _agent_rx = agent(&mut bridge, "agent-a");
+        let (server_id, attached, mut server_rx) = server(&mut bridge, "agent-a");
+        assert_eq!(attached, Some(Attached::Agent("agent-a".into())));
+        assert!(matches!(bridge.route(&server_id, connect(&server_id)), Route::Agent(..)));
+
+        assert!(bridge.kick_agent("agent-a"));
+        let notices = queued(&mut server_rx);
+        assert_eq!(notices.len(), 1);
+        assert_eq!(error(notices.into_iter().next().unwrap()), (None, Some(ErrorCode::GatewayLost)));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-1")), Route::Held));
+
+        // The handshake the server sent first is replayed before what was held
+        let mut agent_rx = agent(&mut bridge, "agent-a");
+        let replayed: Vec<String> = queued(&mut agent_rx).into_iter().map(carried).collect();
+        assert_eq!(replayed, ["connect", "req-1"]);
+    }
+
+    #[test]
+    fn backlog_refuses_messages_past_its_count() {
+        let mut bridge = bridge(60, 2, 1 << 20);
+        let (server_id, _, mut server_rx) = server(&mut bridge, "agent-a");
+        bridge.route(&server_id, connect(&server_id));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-1")), Route::Held));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-2")), Route::Held));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-3")), Route::Refused));
+
+        let errors: Vec<_> = queued(&mut server_rx).into_iter().map(error).collect();
+        assert_eq!(errors, [(Some("req-3".into()), Some(ErrorCode::GatewayUnavailable))]);
+        assert_eq!(bridge.server_summaries()[0].held_messages, 2);
+    }
+
+    #[test]
+    fn backlog_refuses_messages_past_its_bytes() {
+        let frame = chat("server-1", "req-1");
+        let RelayFrame::Forward { payload, .. } = &frame else { unreachable!() };
+        let mut bridge = bridge(60, 10, payload.len() + 1);
+        let (server_id, _, mut server_rx) = server(&mut bridge, "agent-a");
+        bridge.route(&server_id, connect(&server_id));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-1")), Route::Held));
+        assert!(matches!(bridge.route(&server_id, chat(&server_id, "req-2")), Route::Refused));
+
+        let errors: Vec<_> = queued(&mut server_rx).into_iter().map(error).collect();
+        assert_eq!(errors, [(Some("req-2".into()), Some(ErrorCode::GatewayUnavailable))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_requests_fail_once_the_ttl_is_up() {
+        let mut bridge = bridge(60, 10, 1 << 20);
+        let (server_id, _, mut server_rx) = server(&mut bridge, "agent-a");
+        bridge.route(&server_id, connect(&server_id));
+        bridge.route(&server_id, chat(&server_id, "req-1"));
+        bridge.route(&server_id, chat(&server_id, "req-2"));
+
+        tokio::time::advance(Duration::from_secs(59)).await;
+        bridge.expire_held();
+        assert_eq!(bridge.servers.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        bridge.expire_held();
+        assert!(bridge.servers.is_empty());
+        let errors: Vec<_> = queued(&mut server_rx).into_iter().map(error).collect();
+        assert_eq!(errors, [
+            (Some("req-1".into()), Some(ErrorCode::GatewayUnavailable)),
+            (Some("req-2".into()), Some(ErrorCode::GatewayUnavailable)),
+        ]);
+        // Its sender is gone with it, which ends the send task
+        assert_eq!(server_rx.recv().await, None);
+    }
+
+    #[test]
+    fn kicking_an_agent_unregisters_only_a_connected_one() {
+        let mut bridge = bridge(0, 10, 1 << 20);
+        let mut agent_rx = agent(&mut bridge, "agent-a");
+        let (server_id, _, mut server_rx) = server(&mut bridge, "agent-a");
+        assert!(!bridge.kick_agent("agent-b"));
+
+        assert!(bridge.kick_agent("agent-a"));
+        assert!(bridge.agents.is_empty());
+        // Without a backlog TTL its servers are dropped, ending their send tasks
+        assert!(!bridge.servers.contains_key(&server_id));
+        assert!(queued(&mut server_rx).is_empty());
+        assert_eq!(server_rx.recv().now_or_never(), Some(None));
+        assert!(matches!(agent_rx.recv().now_or_never(), Some(None)));
+        assert!(!bridge.kick_agent("agent-a"));
+    }
+
+    #[test]
+    fn summaries_list_peers_and_what_is_attached_to_them() {
+        let mut bridge = bridge(60, 10, 1 << 20);
+        let _agent_b = agent(&mut bridge, "agent-b");
+        let _agent_a = agent(&mut bridge, "agent-a");
+        let (first, _, _first_rx) = server(&mut bridge, "agent-a");
+        let (second, _, _second_rx) = server(&mut bridge, "agent-a");
+        let (held, _, _held_rx) = server(&mut bridge, "agent-c");
+        bridge.route(&held, connect(&held));
+        bridge.route(&held, chat(&held, "req-1"));
+        bridge.agents["agent-a"].tx.send_now(chat(&first, "req-2")).unwrap();
+
+        let agents = bridge.agent_summaries();
+        let agent_ids: Vec<&str> = agents.iter().map(|agent| agent.agent_id.as_str()).collect();
+        assert_eq!(agent_ids, ["agent-a", "agent-b"]);
+        assert_eq!(agents[0].servers, [first.as_str(), second.as_str()]);
+        assert_eq!(agents[0].queue_depth, 1);
+        assert!(agents[1].servers.is_empty());
+
+        let servers = bridge.server_summaries();
+        assert_eq!(servers.len(), 3);
+        let held = servers.iter().find(|server| server.server_id == held).unwrap();
+        assert!(held.held);
+        assert_eq!(held.held_messages, 1);
+        assert_eq!(held.agent_id, "");
+        let first = servers.iter().find(|server| server.server_id == first).unwrap();
+        assert!(!first.held);
+        assert_eq!(first.agent_id, "agent-a");
+        assert_eq!(first.name, "office");
     }
 }
@@ -1,5 +1,12 @@
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use myclaw_common::auth::key_eq;
+
+use crate::queue::OverflowPolicy;
 
 #[derive(Debug, Deserialize)]
 pub struct RelayConfig {
@@ -10,12 +17,250 @@ pub struct RelayConfig {
 pub struct ListenConfig {
     pub server_listen: String,
     pub agent_listen: String,
+    /// PEM certificate chain; with `tls_key`, both listeners serve `wss://`
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// Agent groups a server can bind to by name: group → agent ids
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Agents allowed to register
+    #[serde(default)]
+    pub agents: Vec<AgentCredential>,
+    /// Servers allowed to attach
+    #[serde(default)]
+    pub servers: Vec<ServerCredential>,
+    /// Seconds between pings to each agent and server, keeping NAT mappings open
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Seconds a peer may stay silent, pongs included, before it is dropped
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// What the relay buffers for each peer
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// What the relay holds for a server while its agent is away
+    #[serde(default)]
+    pub backlog: BacklogConfig,
+    /// Admin HTTP listener; off unless configured
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// Address serving Prometheus metrics at `/metrics`; off if unset
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    /// OTLP/gRPC collector to export tracing spans to (needs the `otlp` build feature); off if unset
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminConfig {
+    /// Plain HTTP; keep it on a loopback or private address
+    pub listen: String,
+    /// Bearer token every request must present; required unless `listen` is a loopback address
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Reachable from this host only
+    pub fn loopback(&self) -> bool {
+        match self.listen.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().is_loopback(),
+            Err(_) => self.listen.rsplit_once(':').is_some_and(|(host, _)| host == "localhost"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueConfig {
+    /// Messages waiting for one peer before `overflow` applies
+    #[serde(default = "default_queue_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_queue_capacity(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+fn default_queue_capacity() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BacklogConfig {
+    /// Seconds to wait for an agent to come back before failing what is held;
+    /// 0 detaches servers as soon as their agent goes away
+    #[serde(default = "default_backlog_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Messages held per server
+    #[serde(default = "default_backlog_max_messages")]
+    pub max_messages: usize,
+    /// Payload bytes held per server
+    #[serde(default = "default_backlog_max_bytes")]
+    pub max_bytes: usize,
+}
+
+impl Default for BacklogConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_backlog_ttl_secs(),
+            max_messages: default_backlog_max_messages(),
+            max_bytes: default_backlog_max_bytes(),
+        }
+    }
+}
+
+impl BacklogConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+fn default_backlog_ttl_secs() -> u64 {
+    30
+}
+
+fn default_backlog_max_messages() -> usize {
+    256
+}
+
+fn default_backlog_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    90
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AgentCredential {
+    pub id: String,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServerCredential {
+    pub name: String,
+    pub key: String,
+    /// Agent ids or group names this server may bind to; there must be at least one
+    #[serde(default)]
+    pub agents: Vec<String>,
 }
 
 impl RelayConfig {
     pub fn load(path: &Path) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Self = toml::from_str(&content)?;
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        let relay = &config.relay;
+        // Peers answer each ping, so a timeout of one interval or less drops healthy idle ones
+        if relay.idle_timeout() <= relay.ping_interval() {
+            anyhow::bail!(
+                "idle_timeout_secs ({}) must be longer than ping_interval_secs ({})",
+                relay.idle_timeout_secs,
+                relay.ping_interval_secs
+            );
+        }
+        // A server bound to nothing must not end up on whatever agent happens to be online
+        if let Some(server) = relay.servers.iter().find(|server| server.agents.is_empty()) {
+            anyhow::bail!("server '{}' has no agents to bind to", server.name);
+        }
+        // The admin API can disconnect any peer, so only this host may use it without a token
+        if let Some(admin) = &relay.admin {
+            if admin.token.as_deref() == Some("") {
+                anyhow::bail!("admin token must not be empty");
+            }
+            if admin.token.is_none() && !admin.loopback() {
+                anyhow::bail!("admin listens on {}, which is not a loopback address, without a token", admin.listen);
+            }
+        }
         Ok(config)
     }
 }
+
+impl ListenConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs.max(1))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.max(1))
+    }
+
+    pub fn agent_authorized(&self, agent_id: &str, key: &str) -> bool {
+        self.agents
+            .iter()
+            .any(|a| a.id == agent_id && key_eq(key, &a.key))
+    }
+
+    pub fn server_by_key(&self, key: &str) -> Option<&ServerCredential> {
+        self.servers.iter().find(|s| key_eq(key, &s.key))
+    }
+
+    /// Expand group names into agent ids, keeping order and dropping duplicates.
+    pub fn expand_targets(&self, names: &[String]) -> Vec<String> {
+        let mut ids: Vec<String> = Vec::new();
+        for name in names {
+            let members = self.groups.get(name).cloned().unwrap_or_else(|| vec![name.clone()]);
+            for id in members {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str) -> anyhow::Result<RelayConfig> {
+        RelayConfig::parse(&format!(
+            "[relay]\nserver_listen = \"0.0.0.0:19000\"\nagent_listen = \"0.0.0.0:19001\"\n{}",
+            extra
+        ))
+    }
+
+    #[test]
+    fn admin_needs_a_token_unless_on_loopback() {
+        assert!(parse("[relay.admin]\nlisten = \"127.0.0.1:19100\"\n").is_ok());
+        assert!(parse("[relay.admin]\nlisten = \"[::1]:19100\"\n").is_ok());
+        assert!(parse("[relay.admin]\nlisten = \"localhost:19100\"\n").is_ok());
+        assert!(parse("[relay.admin]\nlisten = \"0.0.0.0:19100\"\n").is_err());
+        assert!(parse("[relay.admin]\nlisten = \"relay.lan:19100\"\n").is_err());
+        assert!(parse("[relay.admin]\nlisten = \"0.0.0.0:19100\"\ntoken = \"\"\n").is_err());
+        assert!(parse("[relay.admin]\nlisten = \"0.0.0.0:19100\"\ntoken = \"secret\"\n").is_ok());
+    }
+
+    #[test]
+    fn servers_must_name_their_agents() {
+        let server = "[[relay.servers]]\nname = \"office\"\nkey = \"k\"\n";
+        assert!(parse(server).is_err());
+        assert!(parse(&format!("{server}agents = []\n")).is_err());
+        assert!(parse(&format!("{server}agents = [\"agent-a\"]\n")).is_ok());
+    }
+
+    #[test]
+    fn idle_timeout_must_outlast_the_ping_interval() {
+        assert!(parse("").is_ok());
+        assert!(parse("ping_interval_secs = 30\nidle_timeout_secs = 30\n").is_err());
+    }
+}
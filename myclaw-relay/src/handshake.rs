@@ -0,0 +1,40 @@
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use myclaw_common::{RejectCode, RelayFrame};
+
+/// How long a peer may take to send its hello frame.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the first frame of a connection, which must be a RelayFrame.
+/// On failure, returns a description of what arrived instead.
+pub async fn read_hello<S>(ws_rx: &mut S) -> Result<RelayFrame, String>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    match timeout(HANDSHAKE_TIMEOUT, ws_rx.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => {
+            serde_json::from_str(&text).map_err(|_| format!("unexpected frame: {}", text))
+        }
+        Ok(Some(Ok(other))) => Err(format!("unexpected message: {:?}", other)),
+        Ok(Some(Err(e))) => Err(e.to_string()),
+        Ok(None) => Err("connection closed before handshake".into()),
+        Err(_) => Err("handshake timed out".into()),
+    }
+}
+
+/// Tell the peer why its handshake was refused, then close the connection.
+pub async fn reject<S>(ws_tx: &mut S, code: RejectCode, message: impl Into<String>)
+where
+    S: Sink<Message> + Unpin,
+{
+    let frame = RelayFrame::Rejected {
+        code,
+        message: message.into(),
+    };
+    if let Ok(json) = serde_json::to_string(&frame) {
+        let _ = ws_tx.send(Message::Text(json)).await;
+    }
+    let _ = ws_tx.close().await;
+}
@@ -0,0 +1,50 @@
+use futures_util::{Stream, StreamExt};
+use tokio::time::{interval_at, timeout, Duration, Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::warn;
+
+/// Ticks once per `period`, first one period from now; each tick is due a ping.
+pub fn pinger(period: Duration) -> Interval {
+    let mut ticks = interval_at(Instant::now() + period, period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks
+}
+
+/// Next message from a peer, or `None` once it closed, failed or sent nothing
+/// (not even a pong) for `idle`.
+pub async fn next_message<S>(ws_rx: &mut S, idle: Duration, peer: &str) -> Option<Message>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    match timeout(idle, ws_rx.next()).await {
+        Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) => None,
+        Ok(Some(Ok(msg))) => Some(msg),
+        Err(_) => {
+            warn!("keepalive: {} silent for {:?}, dropping", peer, idle);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let mut silent = stream::pending::<Result<Message, tungstenite::Error>>();
+        let idle = Duration::from_millis(50);
+        let started = Instant::now();
+        assert!(next_message(&mut silent, idle, "peer").await.is_none());
+        assert!(started.elapsed() >= idle);
+    }
+
+    #[tokio::test]
+    async fn messages_and_close_pass_through() {
+        let mut peer = stream::iter([Ok(Message::Pong(Vec::new())), Ok(Message::Close(None))]);
+        let idle = Duration::from_secs(1);
+        assert_eq!(next_message(&mut peer, idle, "peer").await, Some(Message::Pong(Vec::new())));
+        assert!(next_message(&mut peer, idle, "peer").await.is_none());
+    }
+}
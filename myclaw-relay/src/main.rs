@@ -1,5 +1,10 @@
 mod config;
+mod admin;
+mod backlog;
 mod bridge;
+mod handshake;
+mod keepalive;
+mod queue;
 mod server_side;
 mod agent_side;
 
@@ -7,10 +12,14 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
 use clap::Parser;
-use tracing::info;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use tracing::{info, warn};
 
 use bridge::BridgeHandle;
+use myclaw_common::tls::Acceptor;
+use myclaw_common::trace;
 use config::RelayConfig;
 
 #[derive(Parser)]
@@ -21,28 +30,57 @@ struct Cli {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter("myclaw_relay=debug,info")
-        .init();
-
     let cli = Cli::parse();
     let cfg = RelayConfig::load(&cli.config)?;
+    trace::init("myclaw_relay=debug,info", "myclaw-relay", cfg.relay.otlp_endpoint.as_deref())?;
 
     info!("relay: server_listen={}, agent_listen={}",
         cfg.relay.server_listen, cfg.relay.agent_listen);
 
-    let bridge = Arc::new(RwLock::new(BridgeHandle::new()));
+    if let Some(listen) = &cfg.relay.metrics_listen {
+        PrometheusBuilder::new()
+            .with_http_listener(listen.parse::<std::net::SocketAddr>()?)
+            .install()?;
+        info!("relay: serving metrics on http://{}/metrics", listen);
+    }
+
+    let bridge = Arc::new(RwLock::new(BridgeHandle::new(cfg.relay.backlog.clone())));
+    if cfg.relay.agents.is_empty() || cfg.relay.servers.is_empty() {
+        warn!("relay: no agent or server credentials configured, those connections will be rejected");
+    }
+
+    let acceptor = Acceptor::from_config(cfg.relay.tls_cert.as_deref(), cfg.relay.tls_key.as_deref())?;
+    if let Some(admin) = &cfg.relay.admin {
+        let listener = TcpListener::bind(&admin.listen).await?;
+        let (bridge, token) = (bridge.clone(), admin.token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = admin::run(listener, bridge, token).await {
+                warn!("admin: listener failed: {}", e);
+            }
+        });
+    }
+
+    let listen = Arc::new(cfg.relay);
+    let server_listener = TcpListener::bind(&listen.server_listen).await?;
+    let agent_listener = TcpListener::bind(&listen.agent_listen).await?;
 
-    let server_listener = TcpListener::bind(&cfg.relay.server_listen).await?;
-    let agent_listener = TcpListener::bind(&cfg.relay.agent_listen).await?;
+    info!("relay: listening ({})", acceptor.scheme());
 
-    info!("relay: listening");
+    // Servers whose agent stays away past the backlog TTL are failed and dropped
+    let sweeper = bridge.clone();
+    tokio::spawn(async move {
+        let mut ticks = interval(Duration::from_secs(1));
+        loop {
+            ticks.tick().await;
+            sweeper.write().await.expire_held();
+        }
+    });
 
     tokio::select! {
-        r = server_side::run(server_listener, bridge.clone()) => {
+        r = server_side::run(server_listener, acceptor.clone(), bridge.clone(), listen.clone()) => {
             r?;
         }
-        r = agent_side::run(agent_listener, bridge.clone()) => {
+        r = agent_side::run(agent_listener, acceptor, bridge.clone(), listen.clone()) => {
             r?;
         }
     }
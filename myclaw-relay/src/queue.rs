@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use metrics::Gauge;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+/// What a reader does when the queue of the peer it forwards to is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for room, which stops reading from the sending peer until the slow one catches up
+    #[default]
+    Block,
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Close the queue, which disconnects the slow peer
+    Disconnect,
+}
+
+/// Why a message was not queued.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The peer is gone
+    Closed,
+    /// The queue was full and could not wait for room; the peer is being dropped
+    Overflow,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "peer gone"),
+            SendError::Overflow => write!(f, "queue full, disconnecting peer"),
+        }
+    }
+}
+
+/// Create a queue holding up to `capacity` messages for one peer, reporting its length to `depth`.
+pub fn bounded<T>(capacity: usize, policy: OverflowPolicy, depth: Gauge) -> (QueueTx<T>, QueueRx<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            senders: 1,
+            closed: false,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        items: Notify::new(),
+        room: Notify::new(),
+        depth,
+    });
+    (QueueTx { shared: shared.clone() }, QueueRx { shared })
+}
+
+/// Sending half; the queue ends once every sender is dropped.
+pub struct QueueTx<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half, owned by the peer's send task.
+pub struct QueueRx<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Wakes the receiver when a message arrives or the queue ends
+    items: Notify,
+    /// Wakes blocked senders when room frees up or the queue closes
+    room: Notify,
+    depth: Gauge,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    /// Receiver gone, or closed on overflow
+    closed: bool,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, state: &mut State<T>, item: T) {
+        state.items.push_back(item);
+        self.depth.set(state.items.len() as f64);
+        self.items.notify_waiters();
+    }
+
+    fn close(&self, state: &mut State<T>) {
+        state.closed = true;
+        state.items.clear();
+        self.depth.set(0.0);
+        self.items.notify_waiters();
+        self.room.notify_waiters();
+    }
+}
+
+impl<T> QueueTx<T> {
+    /// Queue a message, applying the overflow policy when the queue is full.
+    pub async fn send(&self, item: T) -> Result<(), SendError> {
+        let shared = &*self.shared;
+        loop {
+            // Registered before checking, so a wakeup in between is not lost
+            let room = shared.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            {
+                let mut state = shared.lock();
+                if state.closed {
+                    return Err(SendError::Closed);
+                }
+                if state.items.len() < shared.capacity {
+                    shared.push(&mut state, item);
+                    return Ok(());
+                }
+                match shared.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        shared.push(&mut state, item);
+                        return Ok(());
+                    }
+                    OverflowPolicy::Disconnect => {
+                        shared.close(&mut state);
+                        return Err(SendError::Overflow);
+                    }
+                }
+            }
+            room.await;
+        }
+    }
+
+    /// Queue a message without waiting for room: where `send` would block,
+    /// the queue is closed as under `Disconnect`.
+    pub fn try_send(&self, item: T) -> Result<(), SendError> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        if state.items.len() < shared.capacity {
+            shared.push(&mut state, item);
+            return Ok(());
+        }
+        match shared.policy {
+            OverflowPolicy::DropOldest => {
+                state.items.pop_front();
+                shared.push(&mut state, item);
+                Ok(())
+            }
+            OverflowPolicy::Block | OverflowPolicy::Disconnect => {
+                shared.close(&mut state);
+                Err(SendError::Overflow)
+            }
+        }
+    }
+
+    /// Messages waiting to be sent
+    pub fn len(&self) -> usize {
+        self.shared.lock().items.len()
+    }
+
+    /// Queue a control frame at once, past the limit if need be.
+    pub fn send_now(&self, item: T) -> Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        self.shared.push(&mut state, item);
+        Ok(())
+    }
+}
+
+impl<T> Clone for QueueTx<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueTx<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.items.notify_waiters();
+        }
+    }
+}
+
+impl<T> QueueRx<T> {
+    /// Next message; `None` once all senders are gone and the queue is drained, or it was closed.
+    pub async fn recv(&mut self) -> Option<T> {
+        let shared = &*self.shared;
+        loop {
+            let items = shared.items.notified();
+            tokio::pin!(items);
+            items.as_mut().enable();
+            {
+                let mut state = shared.lock();
+                if let Some(item) = state.items.pop_front() {
+                    shared.depth.set(state.items.len() as f64);
+                    shared.room.notify_waiters();
+                    return Some(item);
+                }
+                if state.closed || state.senders == 0 {
+                    return None;
+                }
+            }
+            items.await;
+        }
+    }
+}
+
+impl<T> Drop for QueueRx<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        self.shared.close(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics::Gauge;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn queue(policy: OverflowPolicy) -> (QueueTx<u32>, QueueRx<u32>) {
+        bounded(2, policy, Gauge::noop())
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (tx, mut rx) = queue(OverflowPolicy::Block);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), tx.send(3)).await.is_err());
+
+        let sender = tx.clone();
+        let blocked = tokio::spawn(async move { sender.send(3).await });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+        assert_eq!(rx.recv().await, Some(1));
+        timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let (tx, mut rx) = queue(OverflowPolicy::DropOldest);
+        for item in 1..=3 {
+            tx.send(item).await.unwrap();
+        }
+        tx.try_send(4).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, Some(4));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue() {
+        let (tx, mut rx) = queue(OverflowPolicy::Disconnect);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+        assert_eq!(tx.send(3).await, Err(SendError::Overflow));
+        assert_eq!(tx.send_now(4), Err(SendError::Closed));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn try_send_disconnects_instead_of_blocking() {
+        let (tx, mut rx) = queue(OverflowPolicy::Block);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(SendError::Overflow));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn control_frames_go_past_the_limit() {
+        let (tx, mut rx) = queue(OverflowPolicy::Disconnect);
+        for item in 1..=3 {
+            tx.send_now(item).unwrap();
+        }
+        assert_eq!(tx.len(), 3);
+        assert_eq!(rx.recv().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_receiver_closes_the_queue() {
+        let (tx, rx) = queue(OverflowPolicy::Block);
+        tx.send(1).await.unwrap();
+        tx.send(2).await.unwrap();
+
+        let sender = tx.clone();
+        let blocked = tokio::spawn(async move { sender.send(3).await });
+        tokio::task::yield_now().await;
+        drop(rx);
+        let result = timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap();
+        assert_eq!(result, Err(SendError::Closed));
+        assert_eq!(tx.send(4).await, Err(SendError::Closed));
+        assert_eq!(tx.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn receiver_ends_once_senders_are_gone() {
+        let (tx, mut rx) = queue(OverflowPolicy::Block);
+        tx.send(1).await.unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+    }
+}
@@ -1,69 +1,246 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::accept_async;
+use tokio_tungstenite::{accept_hdr_async, tungstenite, WebSocketStream};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{info, warn};
+use tracing::{debug, field, info, info_span, warn};
 
-use crate::bridge::{BridgeHandle, Tx};
+use myclaw_common::compress::{inflate, Compressor, SentCounters};
+use myclaw_common::protocol::{common_capabilities, negotiate_version, Capability, MIN_RELAY_VERSION};
+use myclaw_common::{RejectCode, RelayFrame};
+use myclaw_common::tls::{Acceptor, ServerStream};
+use myclaw_common::trace::{self, FrameHead};
+use crate::bridge::{Attached, BridgeHandle, PeerInfo, Route, ServerTx};
+use crate::config::ListenConfig;
+use crate::handshake::{read_hello, reject};
+use crate::keepalive::{next_message, pinger};
+use crate::queue;
 
-/// Accept a single server-side (myclaw-server) WebSocket connection.
-/// Forward messages from server → agent, and from agent_rx → server.
-pub async fn run(listener: TcpListener, bridge: Arc<RwLock<BridgeHandle>>) -> anyhow::Result<()> {
-    info!("server_side: waiting for myclaw-server connection");
+/// Features the relay offers servers in `server_welcome`
+const SERVER_CAPABILITIES: [Capability; 1] = [Capability::Deflate];
+
+/// Accept myclaw-server WebSocket connections; each server is handled concurrently.
+/// Each server authenticates with a key from relay.toml, which binds it to agents or groups.
+/// The request path (`ws://relay:19000/<agent_id|group>`) may narrow that binding.
+pub async fn run(
+    listener: TcpListener,
+    acceptor: Acceptor,
+    bridge: Arc<RwLock<BridgeHandle>>,
+    cfg: Arc<ListenConfig>,
+) -> anyhow::Result<()> {
+    info!("server_side: waiting for myclaw-server connections");
 
     loop {
         let (stream, addr) = listener.accept().await?;
         info!("server_side: myclaw-server connected from {}", addr);
 
-        let ws = accept_async(stream).await?;
-        let (mut ws_tx, mut ws_rx) = ws.split();
+        let acceptor = acceptor.clone();
+        let bridge = bridge.clone();
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_server(stream, addr, acceptor, bridge, cfg).await {
+                warn!("server_side: connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Authenticate a server and attach it to an agent, then forward messages from server → agent
+/// and from the agent's replies → server.
+async fn handle_server(
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: Acceptor,
+    bridge: Arc<RwLock<BridgeHandle>>,
+    cfg: Arc<ListenConfig>,
+) -> anyhow::Result<()> {
+    let stream = acceptor.accept(stream).await?;
+    let (ws, target) = accept_addressed(stream).await?;
+    let (mut ws_tx, mut ws_rx) = ws.split();
 
-        // Create channel: agent_side will send to this tx, we read from rx and forward to ws
-        let (tx, mut rx): (Tx, _) = tokio::sync::mpsc::unbounded_channel();
+    // --- Handshake: expect ServerHello with a key from relay.toml ---
+    let (cred, version, capabilities) = match read_hello(&mut ws_rx).await {
+        Ok(RelayFrame::ServerHello { key, version, capabilities }) => {
+            let Some(version) = negotiate_version(version, MIN_RELAY_VERSION) else {
+                warn!("server_side: rejected {}: protocol v{}", addr, version);
+                let message = format!("protocol version {} is older than {}", version, MIN_RELAY_VERSION);
+                reject(&mut ws_tx, RejectCode::UnsupportedVersion, message).await;
+                return Ok(());
+            };
+            match cfg.server_by_key(&key) {
+                Some(cred) => (cred, version, common_capabilities(&SERVER_CAPABILITIES, &capabilities)),
+                None => {
+                    warn!("server_side: rejected {}: bad credentials", addr);
+                    reject(&mut ws_tx, RejectCode::Unauthorized, "wrong key").await;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(_) => {
+            warn!("server_side: rejected {}: expected ServerHello", addr);
+            reject(&mut ws_tx, RejectCode::BadHandshake, "expected server_hello").await;
+            return Ok(());
+        }
+        Err(e) => {
+            warn!("server_side: rejected {}: {}", addr, e);
+            reject(&mut ws_tx, RejectCode::BadHandshake, "expected server_hello").await;
+            return Ok(());
+        }
+    };
 
-        // Register server_tx so agent_side can forward to us
-        {
-            let mut b = bridge.write().await;
-            b.server_tx = Some(tx);
+    // The request path may narrow the binding, but never widen it
+    let allowed = cfg.expand_targets(&cred.agents);
+    let candidates = match target {
+        Some(ref name) => {
+            let requested: Vec<String> = cfg
+                .expand_targets(std::slice::from_ref(name))
+                .into_iter()
+                .filter(|id| allowed.contains(id))
+                .collect();
+            if requested.is_empty() {
+                warn!("server_side: rejected '{}' from {}: not allowed to use '{}'",
+                    cred.name, addr, name);
+                reject(&mut ws_tx, RejectCode::Forbidden, format!("not allowed to use '{}'", name)).await;
+                return Ok(());
+            }
+            requested
         }
+        None => allowed,
+    };
 
-        let bridge_read = bridge.clone();
-        let bridge_cleanup = bridge.clone();
+    // Create queue: agent_side will send to this tx, we read from rx and forward to ws
+    let server_id = bridge.write().await.new_server_id();
+    // By credential: server ids are new on every connection, names are bounded by relay.toml
+    let depth = metrics::gauge!("myclaw_relay_queue_depth", "side" => "server", "peer" => cred.name.clone());
+    let (tx, mut rx): (ServerTx, _) = queue::bounded(cfg.queue.capacity, cfg.queue.overflow, depth);
 
-        // Task: forward from rx → ws (agent → server)
-        let send_task = tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if ws_tx.send(Message::Text(msg)).await.is_err() {
-                    break;
+    let info = PeerInfo::new(addr);
+    let stats = info.stats.clone();
+    let attached = bridge.write().await.attach_server(&server_id, tx, &cred.name, info, &candidates);
+    let Some(attached) = attached else {
+        warn!("server_side: no agent available for '{}' from {}", cred.name, addr);
+        reject(&mut ws_tx, RejectCode::AgentUnavailable, "no agent available").await;
+        return Ok(());
+    };
+    let (agent_id, held) = match attached {
+        Attached::Agent(agent_id) => (agent_id, false),
+        Attached::Held => (String::new(), true),
+    };
+    let deflate = capabilities.contains(&Capability::Deflate);
+    let mut compressor = Compressor::new(deflate).counted(SentCounters::new("myclaw_relay", "server"));
+    let welcome = serde_json::to_string(&RelayFrame::ServerWelcome {
+        server_id: server_id.clone(),
+        agent_id: agent_id.clone(),
+        version,
+        capabilities,
+        held,
+    })?;
+    if let Err(e) = ws_tx.send(Message::Text(welcome)).await {
+        bridge.write().await.unregister_server(&server_id);
+        return Err(e.into());
+    }
+    if held {
+        info!("server_side: '{}' from {} held as {} until an agent connects",
+            cred.name, addr, server_id);
+    } else {
+        info!("server_side: '{}' from {} attached to agent '{}' as {}",
+            cred.name, addr, agent_id, server_id);
+    }
+
+    // Task: forward from rx → ws (agent → server), pinging the server in between.
+    // Ends when the server is detached from its agent, or held past the backlog TTL,
+    // and its sender dropped.
+    let task_stats = stats.clone();
+    let mut pings = pinger(cfg.ping_interval());
+    let send_task = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    compressor.compress(msg)
                 }
+                _ = pings.tick() => Message::Ping(Vec::new()),
+            };
+            task_stats.record_out(&msg);
+            if ws_tx.send(msg).await.is_err() {
+                break;
             }
-        });
+        }
+        let _ = ws_tx.close().await;
+    });
 
-        // Read from ws → forward to agent_tx (server → agent)
-        while let Some(Ok(msg)) = ws_rx.next().await {
-            match msg {
-                Message::Text(text) => {
-                    let b = bridge_read.read().await;
-                    if let Some(ref agent_tx) = b.agent_tx {
-                        if agent_tx.send(text).is_err() {
-                            warn!("server_side: agent_tx send failed, agent disconnected?");
-                        }
-                    } else {
-                        warn!("server_side: no agent connected, dropping message");
-                    }
+    // Read from ws → forward to the attached agent (server → agent);
+    // a server silent past the idle timeout is treated as gone
+    let forwarded = metrics::counter!("myclaw_relay_forwarded_bytes_total", "direction" => "server_to_agent");
+    while let Some(msg) = next_message(&mut ws_rx, cfg.idle_timeout(), &server_id).await {
+        stats.record_in(&msg);
+        // Text and Binary gateway messages pass through as the server sent them. Without
+        // deflate, a Binary one starting with a compression marker is the gateway's own.
+        let msg = if deflate {
+            match inflate(msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("server_side: bad compressed message from {}: {}", server_id, e);
+                    continue;
                 }
-                Message::Close(_) => break,
-                _ => {}
             }
+        } else {
+            msg
+        };
+        let len = msg.len() as u64;
+        let traceparent = FrameHead::peek(&msg).and_then(|head| {
+            let request_id = head.traced()?;
+            let span = info_span!("forward", %server_id, kind = %head.kind, request_id, trace_id = field::Empty);
+            let trace = trace::hop(&span, head.traceparent);
+            span.in_scope(|| debug!("server_side: {} → agent", head.kind));
+            Some(trace)
+        });
+        let Some(frame) = RelayFrame::forward(&server_id, msg, traceparent) else {
+            continue;
+        };
+        let route = bridge.read().await.route(&server_id, frame);
+        match route {
+            Route::Agent(agent_tx, frame) => match agent_tx.send(frame).await {
+                Ok(()) => forwarded.increment(len),
+                Err(e) => warn!("server_side: agent_tx send failed: {}", e),
+            },
+            // Counted now, though it reaches an agent only if one comes back
+            Route::Held => forwarded.increment(len),
+            // The bridge already failed the request it carried
+            Route::Refused => {}
+            Route::Detached => warn!("server_side: {} lost its agent, dropping message", server_id),
         }
+    }
 
-        send_task.abort();
-        {
-            let mut b = bridge_cleanup.write().await;
-            b.server_tx = None;
-        }
-        warn!("server_side: myclaw-server disconnected from {}", addr);
+    send_task.abort();
+    bridge.write().await.unregister_server(&server_id);
+    warn!("server_side: {} disconnected from {}", server_id, addr);
+    Ok(())
+}
+
+/// Complete the WebSocket upgrade, capturing the agent addressed by the request path.
+#[allow(clippy::result_large_err)]
+async fn accept_addressed(
+    stream: ServerStream,
+) -> Result<(WebSocketStream<ServerStream>, Option<String>), tungstenite::Error> {
+    let mut target = None;
+    let ws = accept_hdr_async(stream, |req: &Request, resp: Response| {
+        target = target_from_path(req.uri().path());
+        Ok(resp)
+    })
+    .await?;
+    Ok((ws, target))
+}
+
+/// `/myclaw-agent-01` → `Some("myclaw-agent-01")`, `/` → `None`
+fn target_from_path(path: &str) -> Option<String> {
+    let id = path.trim_matches('/');
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
     }
 }
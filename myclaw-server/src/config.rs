@@ -1,4 +1,6 @@
 use clap::Parser;
+use myclaw_common::auth::key_eq;
+use myclaw_common::protocol::Codec;
 use serde::Deserialize;
 use std::path::PathBuf;
 
@@ -14,21 +16,122 @@ pub struct Cli {
 pub struct ServerConfig {
     pub server: ListenConfig,
     pub gateway: GatewayConfig,
+    /// Users allowed to connect, each with a bearer token
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub requests: RequestConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListenConfig {
     pub host: String,
     pub port: u16,
+    /// PEM certificate chain; with `tls_key`, serve `wss://`
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// Seconds between WebSocket pings to each client
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Pings a client may leave unanswered before its session is closed
+    #[serde(default = "default_max_missed_pings")]
+    pub max_missed_pings: u32,
+    /// Address serving Prometheus metrics at `/metrics`; off if unset
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    /// OTLP/gRPC collector to export tracing spans to (needs the `otlp` build feature); off if unset
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_max_missed_pings() -> u32 {
+    3
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct GatewayConfig {
     pub url: String,
     pub node_id: String,
+    /// Key from relay.toml; when set, authenticate with the relay before the gateway handshake
+    #[serde(default)]
+    pub relay_key: Option<String>,
     pub heartbeat_interval_secs: u64,
+    /// Heartbeats the gateway may leave without a pong before the link is reconnected
+    #[serde(default = "default_heartbeat_max_missed")]
+    pub heartbeat_max_missed: u32,
     pub reconnect_base_ms: u64,
     pub reconnect_max_ms: u64,
+    /// `msgpack` to ask the gateway for binary frames; JSON otherwise
+    #[serde(default)]
+    pub codec: Codec,
+    /// Deflate large messages to the relay, or to the gateway when connecting directly
+    #[serde(default)]
+    pub compress: bool,
+}
+
+fn default_heartbeat_max_missed() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryConfig {
+    /// Append-only JSON-lines file holding every chat message
+    #[serde(default = "default_history_path")]
+    pub path: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            path: default_history_path(),
+        }
+    }
+}
+
+fn default_history_path() -> PathBuf {
+    PathBuf::from("data/history.jsonl")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestConfig {
+    /// Longest a chat request may take from being sent until its reply is done
+    #[serde(default = "default_deadline_secs")]
+    pub deadline_secs: u64,
+    /// Longest wait for the next reply chunk (or the first one)
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        Self {
+            deadline_secs: default_deadline_secs(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+        }
+    }
+}
+
+fn default_deadline_secs() -> u64 {
+    300
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserConfig {
+    pub id: String,
+    pub token: String,
 }
 
 impl ServerConfig {
@@ -41,4 +144,12 @@ impl ServerConfig {
     pub fn listen_addr(&self) -> String {
         format!("{}:{}", self.server.host, self.server.port)
     }
+
+    /// Id of the user owning `token`, if any.
+    pub fn user_for_token(&self, token: &str) -> Option<&str> {
+        self.users
+            .iter()
+            .find(|u| key_eq(token, &u.token))
+            .map(|u| u.id.as_str())
+    }
 }
@@ -1,17 +1,31 @@
 use crate::config::GatewayConfig;
-use crate::router::RouterHandle;
+use crate::router::{LinkStatus, RouterHandle};
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use myclaw_common::GatewayFrame;
-use tokio::time::{sleep, Duration};
-use tokio_tungstenite::tungstenite::Message;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use myclaw_common::compress::SentCounters;
+use myclaw_common::protocol::{
+    common_capabilities, negotiate_version, Capability, Decoder, Encoder, ErrorCode,
+    MIN_PEER_VERSION, MIN_RELAY_VERSION, PROTOCOL_VERSION,
+};
+use myclaw_common::{GatewayFrame, RelayFrame};
+use std::collections::VecDeque;
+use tokio::time::{interval_at, sleep, Duration, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::{self, Message};
 use tracing::{debug, error, info, warn};
 
+/// Features this server can use on the gateway link when the gateway has them too
+const GATEWAY_CAPABILITIES: [Capability; 2] = [Capability::Cancel, Capability::Contexts];
+
 pub async fn run(config: GatewayConfig, router: RouterHandle) -> Result<()> {
     let mut backoff_ms = config.reconnect_base_ms;
+    let via_relay = config.relay_key.is_some();
+    router.update_link(|link| *link = LinkStatus::down(via_relay)).await;
+    // Registered up front so it reads 0 rather than missing until the first reconnect
+    let reconnects = metrics::counter!("myclaw_server_gateway_reconnects_total");
 
     loop {
-        match connect_and_run(&config, &router).await {
+        let mut encoder = Encoder::counted(SentCounters::new("myclaw_server", "gateway"));
+        match connect_and_run(&config, &router, &mut encoder).await {
             Ok(()) => {
                 info!("Gateway connection closed normally");
                 backoff_ms = config.reconnect_base_ms;
@@ -21,59 +35,149 @@ pub async fn run(config: GatewayConfig, router: RouterHandle) -> Result<()> {
             }
         }
 
-        router.set_gateway_connected(false).await;
+        // Whether the relay is still up is learned on the next attempt
+        router
+            .update_link(|link| {
+                link.gateway_connected = false;
+                link.agent_attached = link.agent_attached.map(|_| false);
+            })
+            .await;
+        router.gateway_lost().await;
+        reconnects.increment(1);
         warn!("Reconnecting to gateway in {backoff_ms}ms...");
         sleep(Duration::from_millis(backoff_ms)).await;
         backoff_ms = (backoff_ms * 2).min(config.reconnect_max_ms);
     }
 }
 
-async fn connect_and_run(config: &GatewayConfig, router: &RouterHandle) -> Result<()> {
+async fn connect_and_run(
+    config: &GatewayConfig,
+    router: &RouterHandle,
+    encoder: &mut Encoder,
+) -> Result<()> {
     info!("Connecting to gateway: {}", config.url);
-    let (ws, _) = tokio_tungstenite::connect_async(&config.url).await?;
-    let (mut sink, mut stream) = ws.split();
+    let via_relay = config.relay_key.is_some();
+    let ws = tokio_tungstenite::connect_async(&config.url).await;
+    router
+        .update_link(|link| link.relay_reachable = via_relay.then_some(ws.is_ok()))
+        .await;
+    let (mut sink, mut stream) = ws?.0.split();
+
+    let relay_capabilities = match &config.relay_key {
+        Some(key) => {
+            let (capabilities, held) = relay_handshake(key, config.compress, &mut sink, &mut stream).await?;
+            router.update_link(|link| link.agent_attached = Some(!held)).await;
+            Some(capabilities)
+        }
+        None => None,
+    };
 
     // Send connect handshake
-    let connect_frame = GatewayFrame::connect(&config.node_id);
+    let mut ours = GATEWAY_CAPABILITIES.to_vec();
+    ours.extend(config.codec.capabilities());
+    // Behind a relay, compression is agreed with the relay hop instead
+    if config.compress && relay_capabilities.is_none() {
+        ours.push(Capability::Deflate);
+    }
+    let connect_frame = GatewayFrame::connect(&config.node_id, ours.clone());
     let msg = serde_json::to_string(&connect_frame)?;
     sink.send(Message::Text(msg)).await?;
     info!("Sent connect handshake as node: {}", config.node_id);
 
-    // Wait for connected ack
-    let _gateway_session = match stream.next().await {
-        Some(Ok(Message::Text(text))) => {
-            let frame: GatewayFrame = serde_json::from_str(&text)?;
-            match frame {
-                GatewayFrame::Connected { session_id } => {
-                    info!("Gateway connected, session: {session_id}");
-                    router.set_gateway_session(session_id.clone()).await;
-                    router.set_gateway_connected(true).await;
-                    session_id
-                }
-                GatewayFrame::Error { message } => {
-                    anyhow::bail!("Gateway rejected: {message}");
-                }
-                _ => anyhow::bail!("Unexpected frame during handshake"),
-            }
+    // Agreed with the relay hop, they hold whichever gateway answers
+    let relay_capabilities = relay_capabilities.unwrap_or_default();
+    // Until the gateway answers, only the relay hop may compress what it sends us
+    let mut decoder = Decoder::default();
+    decoder.negotiate(&relay_capabilities);
+
+    // Wait for connected ack; while the relay holds us, until an agent connects
+    let ack = loop {
+        match stream.next().await {
+            Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => break decoder.decode::<GatewayFrame>(&msg)?,
+            Some(Ok(Message::Ping(data))) => sink.send(Message::Pong(data)).await?,
+            Some(Ok(Message::Pong(_))) => {}
+            Some(Ok(_)) => anyhow::bail!("Non-data frame during handshake"),
+            Some(Err(e)) => return Err(e.into()),
+            None => anyhow::bail!("Connection closed during handshake"),
         }
-        Some(Ok(_)) => anyhow::bail!("Non-text frame during handshake"),
-        Some(Err(e)) => return Err(e.into()),
-        None => anyhow::bail!("Connection closed during handshake"),
     };
+    match ack {
+        GatewayFrame::Connected { session_id, version, capabilities } => {
+            info!("Gateway connected, session: {session_id}");
+            let link = accept_gateway(router, &ours, &relay_capabilities, session_id, version, capabilities).await?;
+            encoder.negotiate(&link);
+            decoder.negotiate(&link);
+            router
+                .update_link(|link| {
+                    link.gateway_connected = true;
+                    link.agent_attached = link.agent_attached.map(|_| true);
+                })
+                .await;
+        }
+        GatewayFrame::Error { message, .. } => {
+            anyhow::bail!("Gateway rejected: {message}");
+        }
+        _ => anyhow::bail!("Unexpected frame during handshake"),
+    }
 
     // Channel for outbound messages to gateway
-    let (gw_tx, mut gw_rx) = tokio::sync::mpsc::channel::<String>(64);
+    let (gw_tx, mut gw_rx) = tokio::sync::mpsc::channel::<GatewayFrame>(64);
     router.set_gateway_sender(Some(gw_tx)).await;
 
-    let heartbeat_interval = Duration::from_secs(config.heartbeat_interval_secs);
+    // Replay requests parked by the previous link; runs alongside the loop draining gw_rx
+    let resend = router.clone();
+    tokio::spawn(async move { resend.resend_parked().await });
+
+    // A fixed schedule, so steady traffic cannot postpone the heartbeat
+    let period = Duration::from_secs(config.heartbeat_interval_secs.max(1));
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Pings sent since the last pong
+    let mut unanswered = 0;
+    // Timestamps of the pings not yet answered and when each went out, oldest first;
+    // the round trip is measured from the ping a pong echoes, not from the latest one
+    let mut outstanding: VecDeque<(i64, Instant)> = VecDeque::new();
 
     loop {
         tokio::select! {
             // Incoming from gateway
             msg = stream.next() => {
                 match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        handle_gateway_msg(&text, router).await?;
+                    Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                        match decoder.decode(&msg)? {
+                            GatewayFrame::Pong { timestamp } => {
+                                unanswered = 0;
+                                match outstanding.iter().position(|(sent, _)| *sent == timestamp) {
+                                    Some(i) => {
+                                        let rtt = outstanding[i].1.elapsed();
+                                        // Older pings will not be answered any more
+                                        outstanding.drain(..=i);
+                                        debug!("Received pong from gateway after {rtt:?}");
+                                        router.set_gateway_rtt(rtt).await;
+                                    }
+                                    None => debug!("Received pong for unknown ping {timestamp}"),
+                                }
+                            }
+                            // The relay replayed our handshake to the agent that took over
+                            GatewayFrame::Connected { session_id, version, capabilities } => {
+                                info!("Gateway link resumed, session: {session_id}");
+                                // The gateway behind the new agent may be another one, with a
+                                // version, capabilities and session of its own
+                                let link = accept_gateway(router, &ours, &relay_capabilities, session_id, version, capabilities).await?;
+                                encoder.negotiate(&link);
+                                decoder.negotiate(&link);
+                                router
+                                    .update_link(|link| {
+                                        link.gateway_connected = true;
+                                        link.agent_attached = Some(true);
+                                    })
+                                    .await;
+                                // Spawned: resending goes through the channel this loop drains
+                                let resend = router.clone();
+                                tokio::spawn(async move { resend.resend_parked().await });
+                            }
+                            frame => handle_gateway_msg(frame, router).await,
+                        }
                     }
                     Some(Ok(Message::Ping(data))) => {
                         sink.send(Message::Pong(data)).await?;
@@ -87,13 +191,23 @@ async fn connect_and_run(config: &GatewayConfig, router: &RouterHandle) -> Resul
                 }
             }
             // Outbound to gateway
-            Some(msg) = gw_rx.recv() => {
-                sink.send(Message::Text(msg)).await?;
+            Some(frame) = gw_rx.recv() => {
+                sink.send(encoder.encode(&frame)?).await?;
             }
             // Heartbeat
-            _ = sleep(heartbeat_interval) => {
-                let ping = serde_json::to_string(&GatewayFrame::ping_now())?;
-                sink.send(Message::Text(ping)).await?;
+            _ = heartbeat.tick() => {
+                if unanswered >= config.heartbeat_max_missed {
+                    anyhow::bail!("Gateway missed {unanswered} heartbeats");
+                }
+                let ping = GatewayFrame::ping_now();
+                if let GatewayFrame::Ping { timestamp } = ping {
+                    if outstanding.len() > config.heartbeat_max_missed as usize {
+                        outstanding.pop_front();
+                    }
+                    outstanding.push_back((timestamp, Instant::now()));
+                }
+                sink.send(encoder.encode(&ping)?).await?;
+                unanswered += 1;
                 debug!("Sent heartbeat ping");
             }
         }
@@ -103,8 +217,73 @@ async fn connect_and_run(config: &GatewayConfig, router: &RouterHandle) -> Resul
     Ok(())
 }
 
-async fn handle_gateway_msg(text: &str, router: &RouterHandle) -> Result<()> {
-    let frame: GatewayFrame = serde_json::from_str(text)?;
+/// Take on a gateway's `connected`: check its version and record the capabilities it
+/// shares with `ours`. Returns those plus the ones agreed with the relay hop, which
+/// together govern the link.
+async fn accept_gateway(
+    router: &RouterHandle,
+    ours: &[Capability],
+    relay_capabilities: &[Capability],
+    session_id: String,
+    version: u32,
+    capabilities: Vec<Capability>,
+) -> Result<Vec<Capability>> {
+    let Some(version) = negotiate_version(version, MIN_PEER_VERSION) else {
+        anyhow::bail!("Gateway speaks unsupported protocol version {version}");
+    };
+    let capabilities = common_capabilities(ours, &capabilities);
+    info!("Gateway speaks protocol v{version}, capabilities: {capabilities:?}");
+    let mut link = capabilities.clone();
+    link.extend_from_slice(relay_capabilities);
+    router.set_gateway_session(session_id, capabilities).await;
+    Ok(link)
+}
+
+/// Authenticate with myclaw-relay, which attaches this server to one of its agents.
+/// Returns the capabilities the relay agreed to for this hop, and whether it holds
+/// us until an agent connects.
+async fn relay_handshake<S, R>(
+    key: &str,
+    compress: bool,
+    sink: &mut S,
+    stream: &mut R,
+) -> Result<(Vec<Capability>, bool)>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+    R: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let hello = RelayFrame::ServerHello {
+        key: key.to_string(),
+        version: PROTOCOL_VERSION,
+        capabilities: if compress { vec![Capability::Deflate] } else { Vec::new() },
+    };
+    sink.send(Message::Text(serde_json::to_string(&hello)?)).await?;
+
+    match stream.next().await {
+        Some(Ok(Message::Text(text))) => match serde_json::from_str::<RelayFrame>(&text)? {
+            RelayFrame::ServerWelcome { server_id, agent_id, version, capabilities, held } => {
+                if negotiate_version(version, MIN_RELAY_VERSION).is_none() {
+                    anyhow::bail!("Relay speaks unsupported protocol version {version}");
+                }
+                if held {
+                    warn!("Relay holds us as {server_id} until an agent connects");
+                } else {
+                    info!("Relay attached us to agent '{agent_id}' as {server_id}");
+                }
+                Ok((capabilities, held))
+            }
+            RelayFrame::Rejected { code, message } => {
+                anyhow::bail!("Relay rejected ({code:?}): {message}")
+            }
+            _ => anyhow::bail!("Unexpected frame during relay handshake"),
+        },
+        Some(Ok(_)) => anyhow::bail!("Non-text frame during relay handshake"),
+        Some(Err(e)) => Err(e.into()),
+        None => anyhow::bail!("Connection closed during relay handshake"),
+    }
+}
+
+async fn handle_gateway_msg(frame: GatewayFrame, router: &RouterHandle) {
     match frame {
         GatewayFrame::ChatResponse {
             request_id,
@@ -114,15 +293,26 @@ async fn handle_gateway_msg(text: &str, router: &RouterHandle) -> Result<()> {
         } => {
             router.dispatch_reply(&request_id, &content, done).await;
         }
-        GatewayFrame::Pong { .. } => {
-            debug!("Received pong from gateway");
+        GatewayFrame::Error { request_id: Some(request_id), code, message } => {
+            let code = code.unwrap_or(ErrorCode::GatewayUnavailable);
+            router.fail_request(&request_id, code, &message).await;
+        }
+        // The relay lost our agent and holds what we send until one is back
+        GatewayFrame::Error { request_id: None, code: Some(ErrorCode::GatewayLost), message } => {
+            warn!("Gateway link interrupted: {message}");
+            router
+                .update_link(|link| {
+                    link.gateway_connected = false;
+                    link.agent_attached = Some(false);
+                })
+                .await;
+            router.park_in_flight().await;
         }
-        GatewayFrame::Error { message } => {
+        GatewayFrame::Error { message, .. } => {
             warn!("Gateway error: {message}");
         }
-        _ => {
-            debug!("Unhandled gateway frame: {text}");
+        other => {
+            debug!("Unhandled gateway frame: {other:?}");
         }
     }
-    Ok(())
 }
@@ -0,0 +1,413 @@
+use chrono::{DateTime, Utc};
+use myclaw_common::protocol::{ConversationSummary, HistoryEntry, Role};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Conversation of chats that do not name one; exists implicitly for every user
+pub const DEFAULT_CONVERSATION: &str = "default";
+
+/// Page size when a client does not ask for one, and the largest it may ask for
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 200;
+
+/// One persisted chat message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub user_id: String,
+    pub conversation_id: String,
+    pub request_id: String,
+    pub role: Role,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One persisted change to a user's conversation list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConversationEvent {
+    user_id: String,
+    conversation_id: String,
+    #[serde(flatten)]
+    change: ConversationChange,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ConversationChange {
+    Created {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    Renamed {
+        name: String,
+    },
+    Deleted,
+}
+
+/// A line of the history file: a message, or a conversation event (which has an `event` field)
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Message(HistoryRecord),
+    Conversation(ConversationEvent),
+}
+
+struct Conversation {
+    name: Option<String>,
+    created_at: DateTime<Utc>,
+    /// Messages in append order
+    records: Vec<HistoryRecord>,
+}
+
+impl Conversation {
+    fn summary(&self, conversation_id: &str) -> ConversationSummary {
+        ConversationSummary {
+            conversation_id: conversation_id.to_string(),
+            name: self.name.clone(),
+            message_count: self.records.len() as u64,
+            updated_at: self.records.last().map_or(self.created_at, |r| r.timestamp),
+        }
+    }
+}
+
+/// user_id → conversation_id → conversation
+type Index = HashMap<String, HashMap<String, Conversation>>;
+
+/// Append-only JSON-lines log of chat messages, indexed in memory
+/// by user and conversation and rebuilt from the file on startup.
+pub struct HistoryStore {
+    inner: Mutex<StoreInner>,
+}
+
+struct StoreInner {
+    file: tokio::fs::File,
+    index: Index,
+}
+
+/// Whether a message may be stored: its conversation exists, or is the implicit default.
+fn accepts(index: &Index, record: &HistoryRecord) -> bool {
+    record.conversation_id == DEFAULT_CONVERSATION
+        || index
+            .get(&record.user_id)
+            .is_some_and(|convs| convs.contains_key(&record.conversation_id))
+}
+
+fn index_record(index: &mut Index, record: HistoryRecord) {
+    let conv = index
+        .entry(record.user_id.clone())
+        .or_default()
+        .entry(record.conversation_id.clone())
+        .or_insert_with(|| Conversation {
+            name: None,
+            created_at: record.timestamp,
+            records: Vec::new(),
+        });
+    conv.records.push(record);
+}
+
+fn apply_event(index: &mut Index, event: ConversationEvent) {
+    let convs = index.entry(event.user_id).or_default();
+    match event.change {
+        ConversationChange::Created { name } => {
+            convs.entry(event.conversation_id).or_insert(Conversation {
+                name,
+                created_at: event.timestamp,
+                records: Vec::new(),
+            });
+        }
+        ConversationChange::Renamed { name } => {
+            if let Some(conv) = convs.get_mut(&event.conversation_id) {
+                conv.name = Some(name);
+            }
+        }
+        ConversationChange::Deleted => {
+            convs.remove(&event.conversation_id);
+        }
+    }
+}
+
+impl HistoryStore {
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let mut index = Index::new();
+        let mut loaded = 0;
+        // A crash can leave a torn last line behind, even one that ends inside a character
+        let mut torn = false;
+        match tokio::fs::read(path).await {
+            Ok(content) => {
+                torn = content.last().is_some_and(|&b| b != b'\n');
+                for (n, line) in content.split(|&b| b == b'\n').enumerate() {
+                    if line.is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_slice::<Line>(line) {
+                        Ok(Line::Message(record)) if accepts(&index, &record) => {
+                            index_record(&mut index, record);
+                            loaded += 1;
+                        }
+                        // Replies that finished after their conversation was deleted
+                        Ok(Line::Message(_)) => {}
+                        Ok(Line::Conversation(event)) => apply_event(&mut index, event),
+                        Err(e) => warn!("Skipping history line {}: {e}", n + 1),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        info!(
+            "Loaded {loaded} history records of {} users from {}",
+            index.len(),
+            path.display()
+        );
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        if torn {
+            // End the fragment, so the next record starts a line of its own
+            file.write_all(b"\n").await?;
+            file.flush().await?;
+        }
+        Ok(Self {
+            inner: Mutex::new(StoreInner { file, index }),
+        })
+    }
+
+    /// Persist a record, then add it to the in-memory index.
+    /// Fails if the record's conversation does not exist.
+    pub async fn append(&self, record: HistoryRecord) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        if !accepts(&inner.index, &record) {
+            anyhow::bail!("unknown conversation {}", record.conversation_id);
+        }
+        inner.write(&Line::Message(record.clone())).await?;
+        index_record(&mut inner.index, record);
+        Ok(())
+    }
+
+    /// Whether `conversation_id` is one of the user's conversations.
+    pub async fn has_conversation(&self, user_id: &str, conversation_id: &str) -> bool {
+        conversation_id == DEFAULT_CONVERSATION
+            || self
+                .inner
+                .lock()
+                .await
+                .index
+                .get(user_id)
+                .is_some_and(|convs| convs.contains_key(conversation_id))
+    }
+
+    /// Start a new, empty conversation for the user.
+    pub async fn create_conversation(
+        &self,
+        user_id: &str,
+        name: Option<String>,
+    ) -> anyhow::Result<ConversationSummary> {
+        let conversation_id = Uuid::new_v4().to_string();
+        let event = ConversationEvent {
+            user_id: user_id.to_string(),
+            conversation_id: conversation_id.clone(),
+            change: ConversationChange::Created { name },
+            timestamp: Utc::now(),
+        };
+        let mut inner = self.inner.lock().await;
+        inner.write(&Line::Conversation(event.clone())).await?;
+        apply_event(&mut inner.index, event);
+        let conv = &inner.index[user_id][&conversation_id];
+        Ok(conv.summary(&conversation_id))
+    }
+
+    /// Rename one of the user's conversations; `false` if it does not exist.
+    pub async fn rename_conversation(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        name: String,
+    ) -> anyhow::Result<bool> {
+        self.change(user_id, conversation_id, ConversationChange::Renamed { name })
+            .await
+    }
+
+    /// Remove one of the user's conversations from the index; `false` if it does not exist.
+    /// Its messages stay in the file.
+    pub async fn delete_conversation(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+    ) -> anyhow::Result<bool> {
+        self.change(user_id, conversation_id, ConversationChange::Deleted)
+            .await
+    }
+
+    async fn change(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        change: ConversationChange,
+    ) -> anyhow::Result<bool> {
+        let mut inner = self.inner.lock().await;
+        let exists = inner
+            .index
+            .get(user_id)
+            .is_some_and(|convs| convs.contains_key(conversation_id));
+        if !exists {
+            return Ok(false);
+        }
+        let event = ConversationEvent {
+            user_id: user_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            change,
+            timestamp: Utc::now(),
+        };
+        inner.write(&Line::Conversation(event.clone())).await?;
+        apply_event(&mut inner.index, event);
+        Ok(true)
+    }
+
+    /// The user's conversations, most recently active first.
+    pub async fn conversations(&self, user_id: &str) -> Vec<ConversationSummary> {
+        let inner = self.inner.lock().await;
+        let Some(convs) = inner.index.get(user_id) else {
+            return Vec::new();
+        };
+        let mut summaries: Vec<ConversationSummary> = convs
+            .iter()
+            .map(|(id, conv)| conv.summary(id))
+            .collect();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+        summaries
+    }
+
+    /// Up to `limit` entries preceding `before` (or the end), oldest first,
+    /// and whether older entries remain.
+    pub async fn page(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        before: Option<u64>,
+        limit: Option<usize>,
+    ) -> (Vec<HistoryEntry>, bool) {
+        let inner = self.inner.lock().await;
+        let records = inner
+            .index
+            .get(user_id)
+            .and_then(|convs| convs.get(conversation_id))
+            .map(|conv| conv.records.as_slice())
+            .unwrap_or_default();
+
+        let end = before.map_or(records.len(), |b| (b as usize).min(records.len()));
+        let limit = limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+        let start = end.saturating_sub(limit);
+        let entries = records[start..end]
+            .iter()
+            .enumerate()
+            .map(|(i, r)| HistoryEntry {
+                seq: (start + i) as u64,
+                request_id: r.request_id.clone(),
+                role: r.role,
+                content: r.content.clone(),
+                timestamp: r.timestamp,
+            })
+            .collect();
+        (entries, start > 0)
+    }
+}
+
+impl StoreInner {
+    async fn write(&mut self, line: &Line) -> anyhow::Result<()> {
+        let mut json = serde_json::to_string(line)?;
+        json.push('\n');
+        self.file.write_all(json.as_bytes()).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message written before conversations existed, then an event and a message of the new format
+    const LINES: &str = r#"{"user_id":"alice","conversation_id":"default","request_id":"r1","role":"user","content":"hi","timestamp":"2024-01-01T00:00:00Z"}
+{"user_id":"alice","conversation_id":"c1","event":"created","name":"work","timestamp":"2024-01-02T00:00:00Z"}
+{"user_id":"alice","conversation_id":"c1","event":"renamed","name":"job","timestamp":"2024-01-02T00:00:01Z"}
+{"user_id":"alice","conversation_id":"c1","request_id":"r2","role":"bot","content":"hello","timestamp":"2024-01-02T00:00:02Z"}
+{"user_id":"alice","conversation_id":"gone","request_id":"r3","role":"bot","content":"late","timestamp":"2024-01-03T00:00:00Z"}
+{"user_id":"alice","conversation_id":"c1","request_id":"#;
+
+    #[test]
+    fn lines_parse_as_messages_or_events() {
+        let kinds: Vec<_> = LINES
+            .lines()
+            .map(|line| match serde_json::from_str::<Line>(line) {
+                Ok(Line::Message(_)) => "message",
+                Ok(Line::Conversation(_)) => "event",
+                Err(_) => "torn",
+            })
+            .collect();
+        assert_eq!(kinds, ["message", "event", "event", "message", "message", "torn"]);
+    }
+
+    #[tokio::test]
+    async fn old_and_new_lines_load() {
+        let path = std::env::temp_dir()
+            .join(format!("myclaw-history-{}", Uuid::new_v4()))
+            .join("history.jsonl");
+        tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&path, LINES).await.unwrap();
+        let store = HistoryStore::open(&path).await.unwrap();
+
+        let (default, _) = store.page("alice", DEFAULT_CONVERSATION, None, None).await;
+        assert_eq!(default.len(), 1);
+        assert_eq!(default[0].content, "hi");
+
+        let convs = store.conversations("alice").await;
+        let work = convs.iter().find(|c| c.conversation_id == "c1").unwrap();
+        assert_eq!(work.name.as_deref(), Some("job"));
+        assert_eq!(work.message_count, 1);
+        // Messages of conversations that never existed are dropped
+        assert!(!store.has_conversation("alice", "gone").await);
+    }
+
+    #[tokio::test]
+    async fn records_after_a_torn_line_survive_a_reload() {
+        let path = std::env::temp_dir()
+            .join(format!("myclaw-history-{}", Uuid::new_v4()))
+            .join("history.jsonl");
+        tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        // Torn inside the first byte of a three-byte character
+        let mut content = LINES.lines().next().unwrap().as_bytes().to_vec();
+        content.extend_from_slice(b"\n");
+        content.extend_from_slice(br#"{"user_id":"alice","conversation_id":"default","request_id":"r9","content":""#);
+        content.push("你".as_bytes()[0]);
+        tokio::fs::write(&path, content).await.unwrap();
+
+        let store = HistoryStore::open(&path).await.unwrap();
+        let record = HistoryRecord {
+            user_id: "alice".into(),
+            conversation_id: DEFAULT_CONVERSATION.into(),
+            request_id: "r10".into(),
+            role: Role::Bot,
+            content: "你好".into(),
+            timestamp: Utc::now(),
+        };
+        store.append(record).await.unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&path).await.unwrap();
+        let (default, _) = store.page("alice", DEFAULT_CONVERSATION, None, None).await;
+        let contents: Vec<&str> = default.iter().map(|entry| entry.content.as_str()).collect();
+        assert_eq!(contents, ["hi", "你好"]);
+    }
+}
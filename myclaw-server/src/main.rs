@@ -1,23 +1,40 @@
 mod config;
 mod gateway;
+mod history;
 mod router;
 mod server;
 
 use clap::Parser;
 use config::{Cli, ServerConfig};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use myclaw_common::trace;
+use std::net::SocketAddr;
 use tracing::info;
 
+/// Histogram buckets for the gap before each reply chunk, in seconds
+const CHUNK_LATENCY_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter("myclaw_server=debug,info")
-        .init();
-
     let cli = Cli::parse();
     let config = ServerConfig::load(&cli.config)?;
+    trace::init("myclaw_server=debug,info", "myclaw-server", config.server.otlp_endpoint.as_deref())?;
     info!("Loaded config from {:?}", cli.config);
 
-    let (router_handle, _router) = router::Router::new();
+    if let Some(listen) = &config.server.metrics_listen {
+        PrometheusBuilder::new()
+            .with_http_listener(listen.parse::<SocketAddr>()?)
+            .set_buckets_for_metric(
+                Matcher::Full("myclaw_server_chunk_latency_seconds".into()),
+                &CHUNK_LATENCY_BUCKETS,
+            )?
+            .install()?;
+        info!("Serving metrics on http://{listen}/metrics");
+    }
+
+    let history = history::HistoryStore::open(&config.history.path).await?;
+    let (router_handle, router) = router::Router::new(history, config.requests.clone());
+    tokio::spawn(router.run());
 
     let gw_config = config.gateway.clone();
     let gw_router = router_handle.clone();
@@ -1,108 +1,537 @@
+use crate::config::RequestConfig;
+use crate::history::{HistoryRecord, HistoryStore, DEFAULT_CONVERSATION};
+use chrono::Utc;
+use myclaw_common::protocol::{Capability, DisconnectPolicy, ErrorCode, Role};
+use myclaw_common::trace::TraceContext;
 use myclaw_common::{GatewayFrame, ServerMessage};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, warn};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, RwLock, RwLockWriteGuard};
+use tokio::time::{interval, Duration, Instant};
+use tracing::{debug, error, info, warn, Span};
+
+/// Times a `Retry` request is resent after gateway disconnects before it fails
+const MAX_RETRIES: u32 = 3;
+
+/// How often the reaper looks for expired requests
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest time between status broadcasts that only update the gateway RTT
+const RTT_BROADCAST_INTERVAL: Duration = Duration::from_secs(30);
 
 /// Shared handle to the router state
 #[derive(Clone)]
 pub struct RouterHandle {
     inner: Arc<RwLock<RouterState>>,
+    history: Arc<HistoryStore>,
 }
 
 struct RouterState {
     /// Sender to gateway WS
-    gateway_tx: Option<mpsc::Sender<String>>,
-    /// Gateway session ID
+    gateway_tx: Option<mpsc::Sender<GatewayFrame>>,
+    /// Session the gateway issued in its `connected` frame; requests and cancels are sent in it
     gateway_session: Option<String>,
-    /// Whether gateway is connected
-    gateway_connected: bool,
-    /// request_id → client session sender
-    pending: HashMap<String, mpsc::Sender<ServerMessage>>,
-    /// client session_id → sender
-    clients: HashMap<String, mpsc::Sender<ServerMessage>>,
+    /// How far the link towards the gateway is up
+    link: LinkStatus,
+    /// Round trip of the latest heartbeat on the current gateway link
+    gateway_rtt: Option<Duration>,
+    /// When an RTT update was last broadcast on the current gateway link
+    rtt_broadcast_at: Option<Instant>,
+    /// Capabilities shared with the connected gateway; older gateways get no
+    /// `cancel` frames and no `context_id`
+    gateway_capabilities: Vec<Capability>,
+    /// request_id → in-flight request
+    pending: HashMap<String, PendingRequest>,
+    /// client session_id → client
+    clients: HashMap<String, ClientRoute>,
 }
 
-pub struct Router;
+/// How far the server's link towards the gateway is up, as reported to clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStatus {
+    /// Whether myclaw-relay accepts connections; `None` when the gateway is reached directly
+    pub relay_reachable: Option<bool>,
+    /// Whether the relay attached this server to an agent; `None` without a relay
+    pub agent_attached: Option<bool>,
+    /// Gateway handshake done; requests can be sent
+    pub gateway_connected: bool,
+}
+
+impl LinkStatus {
+    /// Nothing reached yet
+    pub fn down(via_relay: bool) -> Self {
+        Self {
+            relay_reachable: via_relay.then_some(false),
+            agent_attached: via_relay.then_some(false),
+            gateway_connected: false,
+        }
+    }
+}
+
+/// Where to deliver messages for a client, and which user it belongs to
+struct ClientRoute {
+    user_id: String,
+    tx: mpsc::Sender<ServerMessage>,
+}
+
+/// Gateway context of one of a user's conversations within the gateway's session.
+/// It is the same from every client and connection of the user, so a conversation keeps
+/// its context across reconnects, while no two users ever share one.
+fn context_id(user_id: &str, conversation_id: &str) -> String {
+    format!("{user_id}/{conversation_id}")
+}
+
+/// A chat message from a client session, to forward to the gateway
+pub struct ChatRequest<'a> {
+    /// Client session the message came in on
+    pub session_id: &'a str,
+    pub conversation_id: &'a str,
+    pub request_id: &'a str,
+    pub content: &'a str,
+    pub on_disconnect: DisconnectPolicy,
+    /// The server's hop in the message's trace
+    pub trace: TraceContext,
+}
+
+/// A chat request waiting for its reply to finish streaming
+struct PendingRequest {
+    user_id: String,
+    conversation_id: String,
+    /// Gateway context of the user's conversation the request was sent in
+    context_id: String,
+    tx: mpsc::Sender<ServerMessage>,
+    /// Original message, kept for resending
+    content: String,
+    on_disconnect: DisconnectPolicy,
+    retries: u32,
+    /// Waiting for the gateway to come back before being resent
+    parked: bool,
+    /// Reply chunks received so far
+    reply: String,
+    started: Instant,
+    /// When the request was (re)sent or last received a chunk
+    last_activity: Instant,
+    /// The server's hop in the request's trace, sent on with every (re)send
+    trace: TraceContext,
+    /// Span of the client's chat message, which the request's later logs belong to
+    span: Span,
+}
+
+impl RouterState {
+    /// Publish the session and request counts reported on `/metrics`
+    fn record_gauges(&self) {
+        metrics::gauge!("myclaw_server_client_sessions").set(self.clients.len() as f64);
+        metrics::gauge!("myclaw_server_pending_requests").set(self.pending.len() as f64);
+    }
+
+    fn status(&self) -> ServerMessage {
+        ServerMessage::Status {
+            gateway_connected: self.link.gateway_connected,
+            gateway_rtt_ms: self.gateway_rtt.map(|rtt| rtt.as_millis() as u64),
+            relay_reachable: self.link.relay_reachable,
+            agent_attached: self.link.agent_attached,
+        }
+    }
+}
+
+/// Reaps pending requests that outlive their deadline or go idle
+pub struct Router {
+    handle: RouterHandle,
+    deadline: Duration,
+    idle_timeout: Duration,
+}
 
 impl Router {
-    pub fn new() -> (RouterHandle, Self) {
+    pub fn new(history: HistoryStore, requests: RequestConfig) -> (RouterHandle, Self) {
         let state = RouterState {
             gateway_tx: None,
             gateway_session: None,
-            gateway_connected: false,
+            link: LinkStatus::default(),
+            gateway_rtt: None,
+            rtt_broadcast_at: None,
+            gateway_capabilities: Vec::new(),
             pending: HashMap::new(),
             clients: HashMap::new(),
         };
         let handle = RouterHandle {
             inner: Arc::new(RwLock::new(state)),
+            history: Arc::new(history),
+        };
+        let router = Router {
+            handle: handle.clone(),
+            deadline: Duration::from_secs(requests.deadline_secs),
+            idle_timeout: Duration::from_secs(requests.idle_timeout_secs),
         };
-        (handle, Router)
+        (handle, router)
+    }
+
+    /// Run the reaper until the process exits.
+    pub async fn run(self) {
+        let mut tick = interval(REAP_INTERVAL);
+        loop {
+            tick.tick().await;
+            self.reap(Instant::now()).await;
+        }
+    }
+
+    /// Fail and drop every request past its deadline or idle for too long.
+    async fn reap(&self, now: Instant) {
+        let mut state = self.handle.inner.write().await;
+        let mut expired = Vec::new();
+        state.pending.retain(|request_id, pending| {
+            let timeout = if now.duration_since(pending.started) >= self.deadline {
+                Some((ErrorCode::DeadlineExceeded, "Request deadline exceeded"))
+            } else if !pending.parked && now.duration_since(pending.last_activity) >= self.idle_timeout {
+                // Parked requests wait on the gateway, not on the reply, so only the deadline applies
+                Some((ErrorCode::IdleTimeout, "No reply from the gateway in time"))
+            } else {
+                None
+            };
+            let Some((code, message)) = timeout else {
+                return true;
+            };
+            warn!(parent: &pending.span, "Request {request_id} of {} timed out: {code:?}", pending.user_id);
+            expired.push((pending.tx.clone(), ServerMessage::request_error(request_id, code, message)));
+            false
+        });
+        state.record_gauges();
+        drop(state);
+
+        for (tx, msg) in expired {
+            self.handle.deliver(&tx, msg).await;
+        }
     }
 }
 
 impl RouterHandle {
-    pub async fn set_gateway_connected(&self, connected: bool) {
-        self.inner.write().await.gateway_connected = connected;
+    /// Apply a change to the link state and tell every client if it changed anything.
+    pub async fn update_link(&self, update: impl FnOnce(&mut LinkStatus)) {
+        let mut state = self.inner.write().await;
+        let before = state.link;
+        update(&mut state.link);
+        if state.link == before {
+            return;
+        }
+        info!("Gateway link changed: {:?}", state.link);
+        if !state.link.gateway_connected {
+            state.gateway_rtt = None;
+            state.rtt_broadcast_at = None;
+        }
+        self.broadcast_status(state).await;
+    }
+
+    pub async fn set_gateway_rtt(&self, rtt: Duration) {
+        let mut state = self.inner.write().await;
+        state.gateway_rtt = Some(rtt);
+        // The first RTT of a link goes out at once; later ones need not follow every heartbeat
+        let now = Instant::now();
+        if state
+            .rtt_broadcast_at
+            .is_some_and(|at| now.duration_since(at) < RTT_BROADCAST_INTERVAL)
+        {
+            return;
+        }
+        state.rtt_broadcast_at = Some(now);
+        self.broadcast_status(state).await;
     }
 
-    pub async fn set_gateway_session(&self, session: String) {
-        self.inner.write().await.gateway_session = Some(session);
+    /// Send the current status to every client, after releasing the lock.
+    async fn broadcast_status(&self, state: RwLockWriteGuard<'_, RouterState>) {
+        let status = state.status();
+        let clients: Vec<_> = state.clients.values().map(|c| c.tx.clone()).collect();
+        drop(state);
+        for tx in clients {
+            self.deliver(&tx, status.clone()).await;
+        }
+    }
+
+    /// Take on the session and capabilities of a gateway that (re)connected.
+    pub async fn set_gateway_session(&self, session_id: String, capabilities: Vec<Capability>) {
+        if !capabilities.contains(&Capability::Contexts) {
+            warn!("Gateway has no contexts capability; all clients share session {session_id}");
+        }
+        let mut state = self.inner.write().await;
+        state.gateway_session = Some(session_id);
+        state.gateway_capabilities = capabilities;
     }
 
-    pub async fn set_gateway_sender(&self, tx: Option<mpsc::Sender<String>>) {
+    pub async fn set_gateway_sender(&self, tx: Option<mpsc::Sender<GatewayFrame>>) {
         self.inner.write().await.gateway_tx = tx;
     }
 
-    pub async fn is_gateway_connected(&self) -> bool {
-        self.inner.read().await.gateway_connected
+    /// The gateway link's state, as reported to clients
+    pub async fn status(&self) -> ServerMessage {
+        self.inner.read().await.status()
     }
 
     pub async fn register_client(
         &self,
         session_id: String,
+        user_id: String,
         tx: mpsc::Sender<ServerMessage>,
     ) {
-        self.inner.write().await.clients.insert(session_id, tx);
+        let mut state = self.inner.write().await;
+        state.clients.insert(session_id, ClientRoute { user_id, tx });
+        state.record_gauges();
     }
 
+    /// Drop the client's route. Its in-flight requests that asked to be retried keep
+    /// running, so their replies are still persisted for the client to reload once it is
+    /// back; the rest have nowhere to go and are dropped.
     pub async fn unregister_client(&self, session_id: &str) {
         let mut state = self.inner.write().await;
-        state.clients.remove(session_id);
-        state.pending.retain(|_, v| !v.is_closed());
+        if let Some(client) = state.clients.remove(session_id) {
+            let before = state.pending.len();
+            // Kept requests let go of the client's channel, so its session can see it closed
+            let (gone, _) = mpsc::channel(1);
+            state.pending.retain(|_, p| {
+                if !p.tx.same_channel(&client.tx) {
+                    return true;
+                }
+                p.tx = gone.clone();
+                p.on_disconnect == DisconnectPolicy::Retry
+            });
+            debug!(
+                "Client session {session_id} of {} unregistered, {} requests dropped",
+                client.user_id,
+                before - state.pending.len()
+            );
+        }
+        state.record_gauges();
     }
 
-    /// Forward a client chat message to the gateway
-    pub async fn send_to_gateway(
+    /// Queue `msg` for a client without waiting: the gateway link and every other client
+    /// must not stall behind one client that stopped reading. A client whose queue is full
+    /// would silently miss the message, so its session is unregistered instead, which
+    /// closes it; the client reconnects and reloads what it missed from the history.
+    /// `false` if not queued.
+    async fn deliver(&self, tx: &mpsc::Sender<ServerMessage>, msg: ServerMessage) -> bool {
+        match tx.try_send(msg) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let session_id = self
+                    .inner
+                    .read()
+                    .await
+                    .clients
+                    .iter()
+                    .find(|(_, client)| client.tx.same_channel(tx))
+                    .map(|(session_id, _)| session_id.clone());
+                if let Some(session_id) = session_id {
+                    warn!("Client session {session_id} is not keeping up with its messages, closing");
+                    self.unregister_client(&session_id).await;
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// The conversation a chat goes to: the named one if `user_id` has it, else the default.
+    pub async fn resolve_conversation(
         &self,
-        request_id: &str,
-        content: &str,
-        client_tx: mpsc::Sender<ServerMessage>,
-    ) -> anyhow::Result<()> {
-        let state = self.inner.read().await;
-        let gw_tx = state
-            .gateway_tx
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Gateway not connected"))?;
-        let session = state
-            .gateway_session
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No gateway session"))?;
-
-        let frame = GatewayFrame::chat_request(request_id, session, content);
-        let msg = serde_json::to_string(&frame)?;
-        gw_tx.send(msg).await?;
+        user_id: &str,
+        conversation_id: Option<String>,
+    ) -> Option<String> {
+        let conversation_id = conversation_id.unwrap_or_else(|| DEFAULT_CONVERSATION.to_string());
+        self.history
+            .has_conversation(user_id, &conversation_id)
+            .await
+            .then_some(conversation_id)
+    }
+
+    /// Forward a chat message of a client session to the gateway
+    pub async fn send_to_gateway(&self, request: ChatRequest<'_>) -> anyhow::Result<()> {
+        let ChatRequest { session_id, conversation_id, request_id, content, on_disconnect, trace } = request;
+        let mut state = self.inner.write().await;
+        let (Some(gw_tx), Some(session)) = (state.gateway_tx.clone(), state.gateway_session.clone())
+        else {
+            anyhow::bail!("Gateway not connected");
+        };
+        let Some(client) = state.clients.get(session_id) else {
+            anyhow::bail!("Client session {session_id} not registered");
+        };
+        let (user_id, client_tx) = (client.user_id.clone(), client.tx.clone());
+        let context_id = context_id(&user_id, conversation_id);
+        let contexts = state.gateway_capabilities.contains(&Capability::Contexts);
+
+        // Register before sending so a fast first chunk finds its route
+        state.pending.insert(
+            request_id.to_string(),
+            PendingRequest {
+                user_id: user_id.clone(),
+                conversation_id: conversation_id.to_string(),
+                context_id: context_id.clone(),
+                tx: client_tx,
+                content: content.to_string(),
+                on_disconnect,
+                retries: 0,
+                parked: false,
+                reply: String::new(),
+                started: Instant::now(),
+                last_activity: Instant::now(),
+                trace,
+                span: Span::current(),
+            },
+        );
+        state.record_gauges();
         drop(state);
 
-        // Register pending request
-        self.inner
-            .write()
-            .await
-            .pending
-            .insert(request_id.to_string(), client_tx);
+        // Persisted first, so a fast reply can never be stored ahead of its message
+        self.record(&user_id, conversation_id, request_id, Role::User, content)
+            .await;
+
+        let context = contexts.then_some(context_id.as_str());
+        let frame = GatewayFrame::chat_request(request_id, &session, context, content, Some(trace));
+        if let Err(e) = gw_tx.send(frame).await {
+            let mut state = self.inner.write().await;
+            state.pending.remove(request_id);
+            state.record_gauges();
+            return Err(e.into());
+        }
+        debug!("Request {request_id} from {user_id} sent to gateway");
         Ok(())
     }
 
+    /// The gateway link dropped: stop sending on it and deal with the requests in flight.
+    pub async fn gateway_lost(&self) {
+        let mut state = self.inner.write().await;
+        state.gateway_tx = None;
+        state.gateway_session = None;
+        drop(state);
+        self.park_in_flight().await;
+    }
+
+    /// Replies in flight will not come: park the requests that asked to be retried
+    /// and fail the rest, telling each client which happened.
+    pub async fn park_in_flight(&self) {
+        let mut state = self.inner.write().await;
+        let mut notices = Vec::new();
+        state.pending.retain(|request_id, pending| {
+            if pending.parked {
+                // Not resent since the last loss; keep waiting
+                true
+            } else if pending.on_disconnect == DisconnectPolicy::Retry && pending.retries < MAX_RETRIES {
+                pending.retries += 1;
+                pending.parked = true;
+                pending.reply.clear();
+                let msg = ServerMessage::Retrying {
+                    request_id: request_id.clone(),
+                    attempt: pending.retries,
+                };
+                notices.push((pending.tx.clone(), msg));
+                true
+            } else {
+                let msg = ServerMessage::request_error(
+                    request_id,
+                    ErrorCode::GatewayLost,
+                    "Gateway connection lost before the reply finished",
+                );
+                notices.push((pending.tx.clone(), msg));
+                false
+            }
+        });
+        state.record_gauges();
+        drop(state);
+
+        if !notices.is_empty() {
+            warn!("Gateway lost with {} requests in flight", notices.len());
+        }
+        for (tx, msg) in notices {
+            self.deliver(&tx, msg).await;
+        }
+    }
+
+    /// Resend parked requests on the current gateway link
+    pub async fn resend_parked(&self) {
+        let mut state = self.inner.write().await;
+        let (Some(gw_tx), Some(session)) = (state.gateway_tx.clone(), state.gateway_session.clone())
+        else {
+            return;
+        };
+        let contexts = state.gateway_capabilities.contains(&Capability::Contexts);
+        let mut frames = Vec::new();
+        for (request_id, pending) in state.pending.iter_mut().filter(|(_, p)| p.parked) {
+            pending.parked = false;
+            pending.last_activity = Instant::now();
+            frames.push(GatewayFrame::chat_request(
+                request_id,
+                &session,
+                contexts.then_some(pending.context_id.as_str()),
+                &pending.content,
+                Some(pending.trace),
+            ));
+        }
+        drop(state);
+
+        if frames.is_empty() {
+            return;
+        }
+        info!("Resending {} requests parked while the gateway was down", frames.len());
+        for frame in frames {
+            // A failed send leaves the rest to the next gateway_lost
+            if gw_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Cancel one of `user_id`'s in-flight requests: tell the gateway to stop, drop the route
+    /// and end the reply with a `cancelled` chunk. Unknown or finished requests are ignored.
+    pub async fn cancel(&self, user_id: &str, request_id: &str) {
+        let mut state = self.inner.write().await;
+        let pending = match state.pending.entry(request_id.to_string()) {
+            Entry::Occupied(entry) if entry.get().user_id == user_id => entry.remove(),
+            _ => {
+                debug!("Cancel of {request_id} by {user_id}: no such request in flight");
+                return;
+            }
+        };
+        state.record_gauges();
+        // A parked request was never sent on the current link, so there is nothing to stop
+        let cancellable = !pending.parked && state.gateway_capabilities.contains(&Capability::Cancel);
+        let gateway = state
+            .gateway_tx
+            .clone()
+            .zip(state.gateway_session.clone())
+            .filter(|_| cancellable);
+        drop(state);
+
+        if let Some((gw_tx, session)) = gateway {
+            let frame = GatewayFrame::cancel(request_id, &session);
+            if gw_tx.send(frame).await.is_err() {
+                debug!("Gateway gone before cancel of {request_id} was sent");
+            }
+        }
+        info!(parent: &pending.span, "Request {request_id} cancelled by {user_id}");
+
+        let msg = ServerMessage::ChatReply {
+            id: uuid::Uuid::new_v4().to_string(),
+            request_id: request_id.to_string(),
+            conversation_id: pending.conversation_id.clone(),
+            content: String::new(),
+            done: true,
+            cancelled: true,
+        };
+        self.deliver(&pending.tx, msg).await;
+    }
+
+    /// End an in-flight request with an error the gateway side reported for it.
+    pub async fn fail_request(&self, request_id: &str, code: ErrorCode, message: &str) {
+        let mut state = self.inner.write().await;
+        let Some(pending) = state.pending.remove(request_id) else {
+            debug!("Error for {request_id}, which is not in flight: {message}");
+            return;
+        };
+        state.record_gauges();
+        drop(state);
+        warn!(parent: &pending.span, "Request {request_id} of {} failed: {message}", pending.user_id);
+        self.deliver(&pending.tx, ServerMessage::request_error(request_id, code, message))
+            .await;
+    }
+
     /// Dispatch a gateway reply to the appropriate client
     pub async fn dispatch_reply(
         &self,
@@ -110,24 +539,375 @@ impl RouterHandle {
         content: &str,
         done: bool,
     ) {
-        let state = self.inner.read().await;
-        if let Some(tx) = state.pending.get(request_id) {
-            let msg = ServerMessage::ChatReply {
-                id: uuid::Uuid::new_v4().to_string(),
-                request_id: request_id.to_string(),
-                content: content.to_string(),
-                done,
-            };
-            if tx.send(msg).await.is_err() {
-                warn!("Client disconnected for request {request_id}");
-            }
-        } else {
+        let mut state = self.inner.write().await;
+        let Some(pending) = state.pending.get_mut(request_id) else {
             debug!("No pending request for {request_id}");
-        }
+            return;
+        };
+        // Since the request was (re)sent for the first chunk, since the previous one after that
+        let chunk = if pending.reply.is_empty() { "first" } else { "next" };
+        metrics::histogram!("myclaw_server_chunk_latency_seconds", "chunk" => chunk)
+            .record(pending.last_activity.elapsed().as_secs_f64());
+        pending.reply.push_str(content);
+        pending.last_activity = Instant::now();
+        let tx = pending.tx.clone();
+        let user_id = pending.user_id.clone();
+        let conversation_id = pending.conversation_id.clone();
+        let finished = if done {
+            state.pending.remove(request_id)
+        } else {
+            None
+        };
+        state.record_gauges();
         drop(state);
 
-        if done {
-            self.inner.write().await.pending.remove(request_id);
+        let msg = ServerMessage::ChatReply {
+            id: uuid::Uuid::new_v4().to_string(),
+            request_id: request_id.to_string(),
+            conversation_id,
+            content: content.to_string(),
+            done,
+            cancelled: false,
+        };
+        if !self.deliver(&tx, msg).await {
+            debug!("Reply to {request_id} not delivered to {user_id}, only persisted");
+        }
+
+        if let Some(pending) = finished {
+            debug!(parent: &pending.span, "Reply to {request_id} finished after {:?}", pending.started.elapsed());
+            self.record(
+                &pending.user_id,
+                &pending.conversation_id,
+                request_id,
+                Role::Bot,
+                &pending.reply,
+            )
+            .await;
+        }
+    }
+
+    /// Stored conversations of `user_id`
+    pub async fn list_conversations(&self, user_id: &str) -> ServerMessage {
+        ServerMessage::Conversations {
+            conversations: self.history.conversations(user_id).await,
+        }
+    }
+
+    /// Start a conversation for `user_id`
+    pub async fn create_conversation(&self, user_id: &str, name: Option<String>) -> ServerMessage {
+        match self.history.create_conversation(user_id, name).await {
+            Ok(conversation) => {
+                info!("{user_id} created conversation {}", conversation.conversation_id);
+                ServerMessage::ConversationCreated { conversation }
+            }
+            Err(e) => {
+                error!("Failed to create conversation for {user_id}: {e}");
+                ServerMessage::error("Failed to create conversation")
+            }
         }
     }
+
+    pub async fn rename_conversation(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        name: String,
+    ) -> ServerMessage {
+        let renamed = self
+            .history
+            .rename_conversation(user_id, conversation_id, name)
+            .await;
+        self.conversation_changed(user_id, conversation_id, renamed).await
+    }
+
+    pub async fn delete_conversation(&self, user_id: &str, conversation_id: &str) -> ServerMessage {
+        let deleted = self
+            .history
+            .delete_conversation(user_id, conversation_id)
+            .await;
+        self.conversation_changed(user_id, conversation_id, deleted).await
+    }
+
+    /// The updated list after a rename or delete, or why it did not happen
+    async fn conversation_changed(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        changed: anyhow::Result<bool>,
+    ) -> ServerMessage {
+        match changed {
+            Ok(true) => self.list_conversations(user_id).await,
+            Ok(false) => ServerMessage::Error {
+                request_id: None,
+                code: Some(ErrorCode::UnknownConversation),
+                message: format!("No conversation {conversation_id}"),
+            },
+            Err(e) => {
+                error!("Failed to update conversation {conversation_id} of {user_id}: {e}");
+                ServerMessage::error("Failed to update conversation")
+            }
+        }
+    }
+
+    /// A page of stored messages from one of `user_id`'s conversations
+    pub async fn load_history(
+        &self,
+        user_id: &str,
+        conversation_id: String,
+        before: Option<u64>,
+        limit: Option<usize>,
+    ) -> ServerMessage {
+        let (entries, has_more) = self
+            .history
+            .page(user_id, &conversation_id, before, limit)
+            .await;
+        ServerMessage::History {
+            conversation_id,
+            entries,
+            has_more,
+        }
+    }
+
+    /// Persist a message; failures are logged, not surfaced to the client
+    async fn record(
+        &self,
+        user_id: &str,
+        conversation_id: &str,
+        request_id: &str,
+        role: Role,
+        content: &str,
+    ) {
+        let record = HistoryRecord {
+            user_id: user_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            request_id: request_id.to_string(),
+            role,
+            content: content.to_string(),
+            timestamp: Utc::now(),
+        };
+        if let Err(e) = self.history.append(record).await {
+            error!("Failed to persist {role:?} message of request {request_id}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A router with a fresh history and a connected gateway whose frames land in the receiver
+    async fn router(
+        deadline_secs: u64,
+        idle_timeout_secs: u64,
+    ) -> (RouterHandle, Router, mpsc::Receiver<GatewayFrame>) {
+        let path = std::env::temp_dir()
+            .join(format!("myclaw-router-{}", uuid::Uuid::new_v4()))
+            .join("history.jsonl");
+        let history = HistoryStore::open(&path).await.unwrap();
+        let requests = RequestConfig {
+            deadline_secs,
+            idle_timeout_secs,
+        };
+        let (handle, router) = Router::new(history, requests);
+        let (gw_tx, gw_rx) = mpsc::channel(16);
+        handle.set_gateway_sender(Some(gw_tx)).await;
+        handle
+            .set_gateway_session("gw-1".into(), vec![Capability::Cancel, Capability::Contexts])
+            .await;
+        (handle, router, gw_rx)
+    }
+
+    async fn send(
+        handle: &RouterHandle,
+        request_id: &str,
+        on_disconnect: DisconnectPolicy,
+    ) -> mpsc::Receiver<ServerMessage> {
+        let (tx, rx) = mpsc::channel(16);
+        let session_id = format!("client-{request_id}");
+        handle
+            .register_client(session_id.clone(), "alice".into(), tx)
+            .await;
+        handle
+            .send_to_gateway(ChatRequest {
+                session_id: &session_id,
+                conversation_id: DEFAULT_CONVERSATION,
+                request_id,
+                content: "hi",
+                on_disconnect,
+                trace: TraceContext::new_root(),
+            })
+            .await
+            .unwrap();
+        rx
+    }
+
+    async fn pending(handle: &RouterHandle) -> usize {
+        handle.inner.read().await.pending.len()
+    }
+
+    fn error_code(msg: ServerMessage) -> Option<ErrorCode> {
+        match msg {
+            ServerMessage::Error { code, .. } => code,
+            other => panic!("expected an error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn conversations_keep_their_context_across_sessions_but_not_users() {
+        let (handle, _router, mut gw_rx) = router(60, 5).await;
+        // The same user on two connections, then another user
+        let _rx1 = send(&handle, "r1", DisconnectPolicy::Fail).await;
+        let _rx2 = send(&handle, "r2", DisconnectPolicy::Fail).await;
+        let (tx, _rx3) = mpsc::channel(16);
+        handle.register_client("client-r3".into(), "bob".into(), tx).await;
+        handle
+            .send_to_gateway(ChatRequest {
+                session_id: "client-r3",
+                conversation_id: DEFAULT_CONVERSATION,
+                request_id: "r3",
+                content: "hi",
+                on_disconnect: DisconnectPolicy::Fail,
+                trace: TraceContext::new_root(),
+            })
+            .await
+            .unwrap();
+        for user in ["alice", "alice", "bob"] {
+            match gw_rx.recv().await {
+                Some(GatewayFrame::ChatRequest { session_id, context_id, .. }) => {
+                    assert_eq!(session_id, "gw-1");
+                    assert_eq!(context_id, Some(format!("{user}/default")));
+                }
+                other => panic!("expected a chat request, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_that_stopped_reading_is_closed_instead_of_blocking_replies() {
+        let (handle, _router, _gw_rx) = router(60, 5).await;
+        let (tx, mut rx) = mpsc::channel(1);
+        handle.register_client("slow".into(), "alice".into(), tx).await;
+        for (request_id, on_disconnect) in [("r1", DisconnectPolicy::Retry), ("r2", DisconnectPolicy::Fail)] {
+            handle
+                .send_to_gateway(ChatRequest {
+                    session_id: "slow",
+                    conversation_id: DEFAULT_CONVERSATION,
+                    request_id,
+                    content: "hi",
+                    on_disconnect,
+                    trace: TraceContext::new_root(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let chunks = async {
+            handle.dispatch_reply("r1", "chunk", false).await;
+            handle.dispatch_reply("r1", "chunk", false).await;
+        };
+        tokio::time::timeout(Duration::from_secs(1), chunks).await.unwrap();
+        // Torn down rather than left with a reply that never finishes
+        assert!(!handle.inner.read().await.clients.contains_key("slow"));
+        assert!(matches!(rx.recv().await, Some(ServerMessage::ChatReply { done: false, .. })));
+        assert!(rx.recv().await.is_none());
+
+        // The retried request still finishes, for the client to reload
+        assert_eq!(pending(&handle).await, 1);
+        handle.dispatch_reply("r1", "chunk", true).await;
+        assert_eq!(pending(&handle).await, 0);
+        let ServerMessage::History { entries, .. } =
+            handle.load_history("alice", DEFAULT_CONVERSATION.into(), None, None).await
+        else {
+            panic!("expected history");
+        };
+        let reply = entries.iter().find(|entry| entry.request_id == "r1" && entry.role == Role::Bot);
+        assert_eq!(reply.map(|entry| entry.content.as_str()), Some("chunkchunkchunk"));
+    }
+
+    #[tokio::test]
+    async fn rtt_updates_are_throttled() {
+        let (handle, _router, _gw_rx) = router(60, 5).await;
+        let (tx, mut rx) = mpsc::channel(16);
+        handle.register_client("c1".into(), "alice".into(), tx).await;
+
+        handle.set_gateway_rtt(Duration::from_millis(10)).await;
+        handle.set_gateway_rtt(Duration::from_millis(20)).await;
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Status { gateway_rtt_ms: Some(10), .. })));
+        assert!(rx.try_recv().is_err());
+
+        // A link change still goes out at once, with the latest RTT
+        handle.update_link(|link| link.gateway_connected = true).await;
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Status { gateway_connected: true, .. })));
+    }
+
+    #[tokio::test]
+    async fn idle_request_times_out() {
+        let (handle, router, _gw_rx) = router(60, 5).await;
+        let mut rx = send(&handle, "r1", DisconnectPolicy::Fail).await;
+
+        router.reap(Instant::now() + Duration::from_secs(4)).await;
+        assert_eq!(pending(&handle).await, 1);
+
+        router.reap(Instant::now() + Duration::from_secs(6)).await;
+        assert_eq!(pending(&handle).await, 0);
+        assert_eq!(error_code(rx.recv().await.unwrap()), Some(ErrorCode::IdleTimeout));
+    }
+
+    #[tokio::test]
+    async fn parked_request_waits_out_only_the_deadline() {
+        let (handle, router, _gw_rx) = router(10, 5).await;
+        let mut rx = send(&handle, "r1", DisconnectPolicy::Retry).await;
+        handle.gateway_lost().await;
+        assert!(matches!(rx.recv().await, Some(ServerMessage::Retrying { attempt: 1, .. })));
+
+        // Idle for longer than the idle timeout, but parked
+        router.reap(Instant::now() + Duration::from_secs(6)).await;
+        assert_eq!(pending(&handle).await, 1);
+
+        router.reap(Instant::now() + Duration::from_secs(11)).await;
+        assert_eq!(pending(&handle).await, 0);
+        assert_eq!(error_code(rx.recv().await.unwrap()), Some(ErrorCode::DeadlineExceeded));
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let (handle, _router, mut gw_rx) = router(60, 5).await;
+        let mut rx = send(&handle, "r1", DisconnectPolicy::Retry).await;
+        assert!(matches!(gw_rx.recv().await, Some(GatewayFrame::ChatRequest { .. })));
+
+        for attempt in 1..=MAX_RETRIES {
+            handle.park_in_flight().await;
+            match rx.recv().await {
+                Some(ServerMessage::Retrying { attempt: n, .. }) => assert_eq!(n, attempt),
+                other => panic!("expected a retry, got {other:?}"),
+            }
+            handle.resend_parked().await;
+            assert!(matches!(gw_rx.recv().await, Some(GatewayFrame::ChatRequest { .. })));
+        }
+
+        handle.park_in_flight().await;
+        assert_eq!(error_code(rx.recv().await.unwrap()), Some(ErrorCode::GatewayLost));
+        assert_eq!(pending(&handle).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_parked_request_sends_no_gateway_cancel() {
+        let (handle, _router, mut gw_rx) = router(60, 5).await;
+        let mut rx = send(&handle, "r1", DisconnectPolicy::Retry).await;
+        assert!(matches!(gw_rx.recv().await, Some(GatewayFrame::ChatRequest { .. })));
+        handle.park_in_flight().await;
+        assert!(matches!(rx.recv().await, Some(ServerMessage::Retrying { .. })));
+
+        // Someone else's cancel is ignored
+        handle.cancel("bob", "r1").await;
+        assert_eq!(pending(&handle).await, 1);
+
+        handle.cancel("alice", "r1").await;
+        assert_eq!(pending(&handle).await, 0);
+        assert!(matches!(
+            rx.recv().await,
+            Some(ServerMessage::ChatReply { done: true, cancelled: true, .. })
+        ));
+        assert!(gw_rx.try_recv().is_err());
+    }
 }
@@ -1,25 +1,59 @@
 use crate::config::ServerConfig;
-use crate::router::RouterHandle;
+use crate::router::{ChatRequest, RouterHandle};
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use myclaw_common::compress::SentCounters;
+use myclaw_common::protocol::{
+    common_capabilities, negotiate_version, Capability, Decoder, Encoder, ErrorCode,
+    MIN_PEER_VERSION, PROTOCOL_VERSION,
+};
+use myclaw_common::tls::{Acceptor, ServerStream};
+use myclaw_common::trace;
 use myclaw_common::{ClientMessage, ServerMessage};
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::Message;
-use tracing::{error, info, warn};
+use tokio::time::{interval_at, timeout, timeout_at, Duration, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
+/// How long a client without an Authorization header may take to send `auth`.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Features offered to clients in the `hello` reply
+const CLIENT_CAPABILITIES: [Capability; 4] = [
+    Capability::Cancel,
+    Capability::Conversations,
+    Capability::Msgpack,
+    Capability::Deflate,
+];
+
 pub async fn run(config: ServerConfig, router: RouterHandle) -> Result<()> {
     let addr = config.listen_addr();
+    let acceptor = Acceptor::from_config(
+        config.server.tls_cert.as_deref(),
+        config.server.tls_key.as_deref(),
+    )?;
+    if config.users.is_empty() {
+        warn!("No users configured, all clients will be rejected");
+    }
+    let config = Arc::new(config);
     let listener = TcpListener::bind(&addr).await?;
-    info!("Client WebSocket server listening on {addr}");
+    info!("Client WebSocket server listening on {}://{addr}", acceptor.scheme());
 
     loop {
         let (stream, peer) = listener.accept().await?;
         info!("New client connection from {peer}");
         let router = router.clone();
+        let acceptor = acceptor.clone();
+        let config = config.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, router).await {
+            if let Err(e) = handle_client(stream, acceptor, config, router).await {
                 error!("Client {peer} error: {e}");
             }
             info!("Client {peer} disconnected");
@@ -27,83 +61,362 @@ pub async fn run(config: ServerConfig, router: RouterHandle) -> Result<()> {
     }
 }
 
-async fn handle_client(stream: tokio::net::TcpStream, router: RouterHandle) -> Result<()> {
-    let ws = tokio_tungstenite::accept_async(stream).await?;
+async fn handle_client(
+    stream: tokio::net::TcpStream,
+    acceptor: Acceptor,
+    config: Arc<ServerConfig>,
+    router: RouterHandle,
+) -> Result<()> {
+    let stream = acceptor.accept(stream).await?;
+    let (ws, header_user) = accept_with_bearer(stream, &config).await?;
     let (mut sink, mut stream) = ws.split();
+
+    // Whether the client's first frame, the only place for `hello`, has been read;
+    // it has when the client authenticated with a frame
+    let mut opened = header_user.is_none();
+    let (user_id, hello) = match header_user {
+        Some(user_id) => (user_id, None),
+        None => match authenticate(&mut stream, &config).await {
+            Ok(authenticated) => authenticated,
+            Err(reason) => {
+                warn!("Client authentication failed: {reason}");
+                let err = ServerMessage::error(format!("Authentication failed: {reason}"));
+                if send_within(&mut sink, Message::Text(serde_json::to_string(&err)?), AUTH_TIMEOUT).await? {
+                    let _ = sink.close().await;
+                }
+                return Ok(());
+            }
+        },
+    };
+
+    // Plain JSON until the client's hello asks for something else
+    let mut encoder = Encoder::counted(SentCounters::new("myclaw_server", "client"));
+    let mut decoder = Decoder::default();
+    // A client that opens with anything but `hello` speaks version 1
+    let mut version = 1;
+
+    // A hello sent before `auth` is answered once the client is authenticated
+    if let Some((requested, capabilities)) = hello {
+        let (reply, shared) = greet(requested, &capabilities);
+        if !send_within(&mut sink, Message::Text(serde_json::to_string(&reply)?), AUTH_TIMEOUT).await? {
+            anyhow::bail!("client took no data for {AUTH_TIMEOUT:?}");
+        }
+        let Some((negotiated, shared)) = shared else {
+            let _ = sink.close().await;
+            return Ok(());
+        };
+        encoder.negotiate(&shared);
+        decoder.negotiate(&shared);
+        version = negotiated;
+    }
+
     let session_id = Uuid::new_v4().to_string();
     let (client_tx, mut client_rx) = mpsc::channel::<ServerMessage>(64);
-    router.register_client(session_id.clone(), client_tx.clone()).await;
+    router
+        .register_client(session_id.clone(), user_id.clone(), client_tx)
+        .await;
 
-    let status = ServerMessage::Status {
-        gateway_connected: router.is_gateway_connected().await,
-    };
-    sink.send(Message::Text(serde_json::to_string(&status)?)).await?;
-    info!("Client session {session_id} established");
+    // Messages to send once the current event is handled
+    let mut out = Vec::new();
+    // With the header, the client's hello is yet to come; `authenticated` waits for it
+    if version >= 2 {
+        let authenticated = ServerMessage::Authenticated {
+            user_id: user_id.clone(),
+        };
+        out.push(encoder.encode(&authenticated)?);
+    }
+    out.push(encoder.encode(&router.status().await)?);
+    info!("Client session {session_id} established for user {user_id}");
 
-    loop {
-        tokio::select! {
-            msg = stream.next() => {
-                match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        handle_client_msg(&text, &router, &client_tx).await;
+    // Anything the client sends proves it is alive; pings only fill the silence
+    let period = Duration::from_secs(config.server.ping_interval_secs.max(1));
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut missed = 0;
+
+    // A failed send ends the session too; either way its route must go
+    let result: Result<()> = async {
+        loop {
+            // The router drops a client that cannot keep up with its messages
+            if client_rx.is_closed() {
+                warn!("Client session {session_id} fell behind on its messages, closing");
+                break;
+            }
+
+            // A half-open client stops taking data once its TCP buffer fills; rather than
+            // wait on it for good, or skip what it has not taken, a send that stalls for a
+            // ping interval closes the session
+            let mut stalled = false;
+            for msg in out.drain(..) {
+                if !send_within(&mut sink, msg, period).await? {
+                    stalled = true;
+                    break;
+                }
+            }
+            if stalled {
+                warn!("Client session {session_id} took no data for {period:?}, closing");
+                break;
+            }
+
+            tokio::select! {
+                msg = stream.next() => {
+                    if matches!(msg, Some(Ok(_))) {
+                        missed = 0;
                     }
-                    Some(Ok(Message::Ping(data))) => {
-                        sink.send(Message::Pong(data)).await?;
+                    match msg {
+                        Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                            let first = !std::mem::replace(&mut opened, true);
+                            match decoder.decode::<ClientMessage>(&msg) {
+                                Ok(ClientMessage::Hello { version: requested, capabilities }) if first => {
+                                    let (reply, shared) = greet(requested, &capabilities);
+                                    let Some((negotiated, shared)) = shared else {
+                                        if send_within(&mut sink, encoder.encode(&reply)?, period).await? {
+                                            let _ = sink.close().await;
+                                        }
+                                        break;
+                                    };
+                                    out.push(encoder.encode(&reply)?);
+                                    encoder.negotiate(&shared);
+                                    decoder.negotiate(&shared);
+                                    version = negotiated;
+                                    if version >= 2 {
+                                        let authenticated = ServerMessage::Authenticated {
+                                            user_id: user_id.clone(),
+                                        };
+                                        out.push(encoder.encode(&authenticated)?);
+                                    }
+                                }
+                                Ok(ClientMessage::Hello { .. }) => {
+                                    warn!("Client session {session_id} sent hello after its first frame");
+                                    let err = ServerMessage::error("hello must be the first message");
+                                    out.push(encoder.encode(&err)?);
+                                }
+                                Ok(msg) => {
+                                    // Answered here rather than through the session's own queue,
+                                    // which this loop drains
+                                    let reply = handle_client_msg(msg, &session_id, &user_id, &router).await;
+                                    if let Some(reply) = reply.filter(|r| r.min_version() <= version) {
+                                        out.push(encoder.encode(&reply)?);
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!("Invalid client message: {e}");
+                                    let err = ServerMessage::error(format!("Invalid message: {e}"));
+                                    out.push(encoder.encode(&err)?);
+                                }
+                            }
+                        }
+                        Some(Ok(Message::Ping(data))) => out.push(Message::Pong(data)),
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Err(e)) => {
+                            warn!("Client WS error: {e}");
+                            break;
+                        }
+                        _ => {}
                     }
-                    Some(Ok(Message::Close(_))) | None => break,
-                    Some(Err(e)) => {
-                        warn!("Client WS error: {e}");
+                }
+                Some(server_msg) = client_rx.recv() => {
+                    if server_msg.min_version() > version {
+                        debug!("Not sending {server_msg:?} to v{version} client session {session_id}");
+                        continue;
+                    }
+                    out.push(encoder.encode(&server_msg)?);
+                }
+                _ = heartbeat.tick() => {
+                    if missed >= config.server.max_missed_pings {
+                        warn!("Client session {session_id} missed {missed} pings, closing");
                         break;
                     }
-                    _ => {}
+                    missed += 1;
+                    out.push(Message::Ping(Vec::new()));
                 }
             }
-            Some(server_msg) = client_rx.recv() => {
-                let json = serde_json::to_string(&server_msg)?;
-                sink.send(Message::Text(json)).await?;
-            }
         }
+        Ok(())
     }
+    .await;
 
     router.unregister_client(&session_id).await;
-    Ok(())
+    result
 }
 
-async fn handle_client_msg(
-    text: &str,
-    router: &RouterHandle,
-    client_tx: &mpsc::Sender<ServerMessage>,
-) {
-    let msg: ClientMessage = match serde_json::from_str(text) {
-        Ok(m) => m,
-        Err(e) => {
-            warn!("Invalid client message: {e}");
-            let _ = client_tx
-                .send(ServerMessage::Error {
-                    message: format!("Invalid message: {e}"),
-                })
-                .await;
-            return;
+/// Send `msg` to a client, or give up with `false` if it takes no data for `limit`.
+async fn send_within<S>(sink: &mut S, msg: Message, limit: Duration) -> Result<bool>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    match timeout(limit, sink.send(msg)).await {
+        Ok(sent) => sent.map(|()| true).map_err(Into::into),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Complete the WebSocket upgrade. A valid `Authorization: Bearer` header yields the
+/// user id; an invalid one is refused with HTTP 401; a missing one yields `None`.
+#[allow(clippy::result_large_err)]
+async fn accept_with_bearer(
+    stream: ServerStream,
+    config: &ServerConfig,
+) -> Result<(WebSocketStream<ServerStream>, Option<String>)> {
+    let mut user_id = None;
+    let ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+        let Some(header) = req.headers().get(AUTHORIZATION) else {
+            return Ok(resp);
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        match config.user_for_token(token.trim()) {
+            Some(id) => {
+                user_id = Some(id.to_string());
+                Ok(resp)
+            }
+            None => {
+                warn!("Rejected upgrade with invalid bearer token");
+                let mut err = ErrorResponse::new(Some("invalid bearer token".into()));
+                *err.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(err)
+            }
         }
-    };
+    })
+    .await?;
+    Ok((ws, user_id))
+}
+
+/// A client's `hello`: its protocol version and capabilities
+type ClientHello = (u32, Vec<Capability>);
+
+/// Wait for a `ClientMessage::Auth` frame, optionally preceded by `hello`, and resolve
+/// its token to a user id.
+async fn authenticate<S>(
+    stream: &mut S,
+    config: &ServerConfig,
+) -> Result<(String, Option<ClientHello>), String>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let deadline = Instant::now() + AUTH_TIMEOUT;
+    let mut hello = None;
+    loop {
+        let text = match timeout_at(deadline, stream.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => text,
+            Ok(_) => return Err("connection closed before auth".into()),
+            Err(_) => return Err("timed out waiting for auth".into()),
+        };
+        match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Hello { version, capabilities }) if hello.is_none() => {
+                hello = Some((version, capabilities));
+            }
+            Ok(ClientMessage::Auth { token }) => {
+                return config
+                    .user_for_token(&token)
+                    .map(|user_id| (user_id.to_string(), hello))
+                    .ok_or_else(|| "invalid token".to_string());
+            }
+            _ => return Err("expected auth message".into()),
+        }
+    }
+}
 
+/// Answer a client's `hello` with the negotiated version, or reject a version
+/// this server cannot speak. Also returns the version and capabilities both ends
+/// share, or `None` when the connection must close.
+fn greet(version: u32, capabilities: &[Capability]) -> (ServerMessage, Option<ClientHello>) {
+    match negotiate_version(version, MIN_PEER_VERSION) {
+        Some(version) => {
+            let capabilities = common_capabilities(&CLIENT_CAPABILITIES, capabilities);
+            debug!("Client speaks protocol v{version}, shared capabilities: {capabilities:?}");
+            let reply = ServerMessage::Hello {
+                version,
+                capabilities: capabilities.clone(),
+            };
+            (reply, Some((version, capabilities)))
+        }
+        None => {
+            warn!("Rejected client with protocol version {version}");
+            let reply = ServerMessage::Error {
+                request_id: None,
+                code: Some(ErrorCode::UnsupportedVersion),
+                message: format!(
+                    "Protocol version {version} is not supported (need {MIN_PEER_VERSION} to {PROTOCOL_VERSION})"
+                ),
+            };
+            (reply, None)
+        }
+    }
+}
+
+async fn handle_client_msg(
+    msg: ClientMessage,
+    session_id: &str,
+    user_id: &str,
+    router: &RouterHandle,
+) -> Option<ServerMessage> {
     match msg {
-        ClientMessage::Chat { id, content } => {
-            info!("Chat request {id}: {content}");
-            if let Err(e) = router
-                .send_to_gateway(&id, &content, client_tx.clone())
-                .await
-            {
-                warn!("Failed to forward to gateway: {e}");
-                let _ = client_tx
-                    .send(ServerMessage::Error {
-                        message: e.to_string(),
-                    })
-                    .await;
+        ClientMessage::Chat {
+            id,
+            content,
+            conversation_id,
+            on_disconnect,
+            traceparent,
+        } => {
+            let span = info_span!("chat", request_id = %id, user_id, trace_id = field::Empty);
+            let trace = trace::hop(&span, traceparent);
+            async {
+                info!("Chat request {id} from {user_id}: {content}");
+                let Some(conversation_id) = router.resolve_conversation(user_id, conversation_id).await
+                else {
+                    return Some(ServerMessage::request_error(&id, ErrorCode::UnknownConversation, "No such conversation"));
+                };
+                let request = ChatRequest {
+                    session_id,
+                    conversation_id: &conversation_id,
+                    request_id: &id,
+                    content: &content,
+                    on_disconnect,
+                    trace,
+                };
+                if let Err(e) = router.send_to_gateway(request).await {
+                    warn!("Failed to forward to gateway: {e}");
+                    return Some(ServerMessage::request_error(&id, ErrorCode::GatewayUnavailable, e.to_string()));
+                }
+                None
             }
+            .instrument(span)
+            .await
+        }
+        ClientMessage::Ping { timestamp } => Some(ServerMessage::Pong { timestamp }),
+        ClientMessage::Cancel { request_id } => {
+            router.cancel(user_id, &request_id).await;
+            None
+        }
+        ClientMessage::ListConversations => Some(router.list_conversations(user_id).await),
+        ClientMessage::CreateConversation { name } => {
+            Some(router.create_conversation(user_id, name).await)
+        }
+        ClientMessage::RenameConversation { conversation_id, name } => {
+            Some(router.rename_conversation(user_id, &conversation_id, name).await)
+        }
+        ClientMessage::DeleteConversation { conversation_id } => {
+            Some(router.delete_conversation(user_id, &conversation_id).await)
+        }
+        ClientMessage::LoadHistory {
+            conversation_id,
+            before,
+            limit,
+        } => {
+            let page = router
+                .load_history(user_id, conversation_id, before, limit)
+                .await;
+            Some(page)
         }
-        ClientMessage::Ping => {
-            let _ = client_tx.send(ServerMessage::Pong).await;
+        ClientMessage::Auth { .. } => {
+            debug!("Ignoring auth from already authenticated user {user_id}");
+            None
         }
+        // Answered by the connection loop
+        ClientMessage::Hello { .. } => None,
     }
 }